@@ -1,10 +1,24 @@
 use anyhow::Context;
 use clap::Parser;
 use rustc_josh_sync::SyncContext;
-use rustc_josh_sync::config::{JoshConfig, load_config};
-use rustc_josh_sync::josh::{JoshProxy, try_install_josh};
-use rustc_josh_sync::sync::{DEFAULT_UPSTREAM_REPO, GitSync, RustcPullError};
-use rustc_josh_sync::utils::{get_current_head_sha, prompt};
+use rustc_josh_sync::changelog::Changelog;
+use rustc_josh_sync::command::SystemRunner;
+use rustc_josh_sync::config::{
+    DEFAULT_UPSTREAM_REPO, JoshConfig, MessagesConfig, PullRequestConfig, check_config, load_config,
+};
+use rustc_josh_sync::doctor::{CheckStatus, run_checks};
+use rustc_josh_sync::error::{Error, ErrorKind, error_kind};
+use rustc_josh_sync::forge::Forge;
+use rustc_josh_sync::git::GixBackend;
+use rustc_josh_sync::github::{GitHubClient, PullRequestOutcome, SyncPullRequest};
+use rustc_josh_sync::josh::{JOSH_VERSION, JoshProxy, JoshTool};
+use rustc_josh_sync::sync;
+use rustc_josh_sync::sync::{
+    GitSync, PullPreview, PullResult, PushPreview, PushStage, PushState, SubtreePush, abort_pull,
+    cleanup_push,
+};
+use rustc_josh_sync::template::render;
+use rustc_josh_sync::utils::{get_current_head_sha, set_hermetic_git};
 use std::path::{Path, PathBuf};
 
 const DEFAULT_CONFIG_PATH: &str = "josh-sync.toml";
@@ -26,11 +40,12 @@ enum Command {
         /// Override the upstream repository from which we pull changes.
         /// Can be used to perform experimental pulls e.g. to test changes in the subtree repository
         /// that have not yet been merged in `rust-lang/rust`.
-        #[clap(long, default_value(DEFAULT_UPSTREAM_REPO))]
-        upstream_repo: String,
+        /// By default, the `upstream-repo` from the config is used.
+        #[clap(long)]
+        upstream_repo: Option<String>,
 
-        /// Override the rustc commit that we should pull from.
-        /// By default, josh-sync will pull from rustc's HEAD (latest commit).
+        /// Override the rustc commit that we should pull from, as a full commit SHA.
+        /// By default, josh-sync will pull from the latest commit of the upstream branch.
         #[clap(long)]
         upstream_commit: Option<String>,
 
@@ -40,21 +55,181 @@ enum Command {
         /// in that case, pass this flag.
         #[clap(long)]
         allow_noop: bool,
+
+        /// Only report what would be pulled and whether the merge would conflict,
+        /// without modifying the working tree or the current branch.
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Finish a pull that was interrupted by merge conflicts, after they were resolved
+        /// and the resolved files were added with `git add`.
+        #[clap(
+            long = "continue",
+            conflicts_with_all = ["abort", "dry_run", "upstream_commit", "allow_noop"]
+        )]
+        continue_pull: bool,
+
+        /// Roll back a pull that was interrupted by merge conflicts.
+        #[clap(long, conflicts_with_all = ["dry_run", "upstream_commit", "allow_noop"])]
+        abort: bool,
+
+        /// Format of the printed result.
+        #[clap(long, value_enum, default_value_t)]
+        output: OutputFormat,
         #[clap(flatten)]
         shared: SharedArgs,
     },
+    /// Create a pull request with pulled changes in the subtree repository, or update an open
+    /// pull request from the same branch with the same title and author.
+    /// The branch with the changes has to be pushed to the subtree repository first.
+    /// Prints the URL of the pull request.
+    PullPr {
+        /// Branch with the pulled changes, possibly prefixed with `<owner>:` if it is located
+        /// in a fork of the subtree repository.
+        #[clap(long, default_value = "rustc-pull")]
+        head: String,
+
+        /// Branch into which the pulled changes should be merged.
+        /// By default, the default branch of the subtree repository is used.
+        #[clap(long)]
+        base: Option<String>,
+
+        /// GitHub login of the author of the pull request, used to find an existing one.
+        /// By default, the user that owns the GitHub token is used.
+        #[clap(long)]
+        author: Option<String>,
+
+        /// Description of the pull request.
+        /// By default, the messages of the merge commits created by the latest pull on the
+        /// current branch are used, which contain the changelog of the pulled changes.
+        #[clap(long)]
+        body: Option<String>,
+
+        /// Path to the josh-sync TOML config file.
+        #[clap(long, default_value(DEFAULT_CONFIG_PATH))]
+        config_path: PathBuf,
+
+        /// Path to a file storing the last synchronized rustc commit.
+        /// Only used if the config does not contain any `[[subtree]]` entries.
+        #[clap(long, default_value(DEFAULT_RUST_VERSION_PATH))]
+        rust_version_path: PathBuf,
+
+        /// Print executed commands.
+        #[clap(long, short = 'v', env = "JOSH_SYNC_VERBOSE")]
+        verbose: bool,
+    },
     /// Push changes into the main `rust-lang/rust` repository `branch` of a `rustc` fork under
     /// the given GitHub `username`.
     /// The pushed branch should then be merged into the `rustc` repository.
     Push {
-        /// Branch that should be pushed to your remote
-        branch: String,
+        /// Branch that should be pushed to your remote.
+        /// When pushing multiple subtrees, each subtree is pushed to `<branch>-<subtree-name>`.
+        #[clap(required_unless_present_any = ["resume", "cleanup"])]
+        branch: Option<String>,
 
         /// Your GitHub usename where the fork is located
-        username: String,
+        #[clap(required_unless_present_any = ["resume", "cleanup"])]
+        username: Option<String>,
+
+        /// Only estimate what would be pushed from the local history, without pushing anything.
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Update the branch if it already exists in your fork, e.g. to add new changes to an
+        /// existing subtree update PR. The branch has to be based on the same upstream commit
+        /// as the one in the `rust-version` file.
+        #[clap(long, conflicts_with = "dry_run")]
+        update: bool,
+
+        /// Open the pull request against the upstream repository using the GitHub API,
+        /// and print its URL. The GitHub token is taken from the `GITHUB_TOKEN` or `GH_TOKEN`
+        /// environment variable, or from the `gh` tool.
+        #[clap(long, conflicts_with_all = ["dry_run", "update"])]
+        open_pr: bool,
+
+        /// Resume a failed push from the stage at which it has failed.
+        #[clap(long, conflicts_with_all = ["branch", "username", "dry_run", "update", "open_pr", "cleanup"])]
+        resume: bool,
+
+        /// Delete the branch(es) created in your fork by a failed push, and forget the push.
+        #[clap(long, conflicts_with_all = ["branch", "username", "dry_run", "update", "open_pr"])]
+        cleanup: bool,
+
+        /// Format of the printed result.
+        #[clap(long, value_enum, default_value_t)]
+        output: OutputFormat,
+        #[clap(flatten)]
+        shared: SharedArgs,
+    },
+    /// Show how far the subtree(s) have diverged from upstream since the last sync.
+    Status {
+        /// Format of the printed status.
+        #[clap(long, value_enum, default_value_t)]
+        output: OutputFormat,
         #[clap(flatten)]
         shared: SharedArgs,
     },
+    /// Install the josh-proxy and josh-filter binaries used by josh-sync, unless they
+    /// are already installed.
+    /// This can be used to pre-provision CI images.
+    InstallJosh {
+        /// Reinstall the binaries even if they are already installed.
+        #[clap(long)]
+        force: bool,
+
+        /// Print executed commands.
+        #[clap(long, short = 'v', env = "JOSH_SYNC_VERBOSE")]
+        verbose: bool,
+    },
+    /// Check the config file and the `rust-version` file(s) for problems, and report all of them.
+    CheckConfig {
+        /// Path to the josh-sync TOML config file.
+        #[clap(long, default_value(DEFAULT_CONFIG_PATH))]
+        config_path: PathBuf,
+
+        /// Path to a file storing the last synchronized rustc commit.
+        /// Only used if the config does not contain any `[[subtree]]` entries.
+        #[clap(long, default_value(DEFAULT_RUST_VERSION_PATH))]
+        rust_version_path: PathBuf,
+
+        /// Path to a local rustc checkout.
+        /// If specified, also checks that the subtree path(s) exist in rustc.
+        #[clap(long, env = "RUSTC_GIT")]
+        rustc_git: Option<PathBuf>,
+
+        /// Print executed commands.
+        #[clap(long, short = 'v', env = "JOSH_SYNC_VERBOSE")]
+        verbose: bool,
+    },
+    /// Diagnose problems with the environment in which josh-sync runs (git, Josh, the
+    /// config, cache directories, `RUSTC_GIT` and `gh`), and suggest how to fix them.
+    Doctor {
+        /// Path to the josh-sync TOML config file.
+        #[clap(long, default_value(DEFAULT_CONFIG_PATH))]
+        config_path: PathBuf,
+
+        /// Path to a file storing the last synchronized rustc commit.
+        /// Only used if the config does not contain any `[[subtree]]` entries.
+        #[clap(long, default_value(DEFAULT_RUST_VERSION_PATH))]
+        rust_version_path: PathBuf,
+
+        /// Path to a local rustc checkout.
+        #[clap(long, env = "RUSTC_GIT")]
+        rustc_git: Option<PathBuf>,
+
+        /// Print executed commands.
+        #[clap(long, short = 'v', env = "JOSH_SYNC_VERBOSE")]
+        verbose: bool,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+enum OutputFormat {
+    /// Human-readable text.
+    #[default]
+    Text,
+    /// Machine-readable JSON.
+    Json,
 }
 
 #[derive(clap::Parser)]
@@ -64,11 +239,18 @@ struct SharedArgs {
     config_path: PathBuf,
 
     /// Path to a file storing the last synchronized rustc commit.
+    /// Only used if the config does not contain any `[[subtree]]` entries.
     #[clap(long, default_value(DEFAULT_RUST_VERSION_PATH))]
     rust_version_path: PathBuf,
 
+    /// Name of a subtree from the config that should be synchronized.
+    /// Can be passed multiple times. By default, all subtrees are synchronized.
+    #[clap(long = "subtree")]
+    subtrees: Vec<String>,
+
     /// Path to the josh-proxy binary to be used.
-    /// If not specified, it will be installed automatically.
+    /// If not specified, a josh-proxy binary with the expected version is looked up,
+    /// and installed automatically if it is not found.
     ///
     /// Warning: if you use a custom Josh version, ensure that it works properly!
     #[clap(long)]
@@ -79,17 +261,71 @@ struct SharedArgs {
     verbose: bool,
 }
 
-fn main() -> anyhow::Result<()> {
+fn main() {
     let args = Args::parse();
+    let json_report = match &args.cmd {
+        Command::Pull {
+            output: OutputFormat::Json,
+            ..
+        } => Some(JsonReport::Pull),
+        Command::Push {
+            output: OutputFormat::Json,
+            ..
+        } => Some(JsonReport::Push),
+        _ => None,
+    };
+    if let Err(error) = run(args) {
+        // Scripts that read the JSON report also need to learn about failures that happen
+        // before any subtree is synchronized, e.g. because the config is invalid.
+        if let Some(json_report) = json_report {
+            let report = match json_report {
+                JsonReport::Pull => serde_json::to_string_pretty(&PullReport::failed(&error)),
+                JsonReport::Push => serde_json::to_string_pretty(&PushReport::failed(&error)),
+            };
+            if let Ok(report) = report {
+                println!("{report}");
+            }
+        }
+        eprintln!("Error: {error:?}");
+        std::process::exit(exit_code(error_kind(&error)));
+    }
+}
+
+/// Exit code of a failed command, documented in the README.
+fn exit_code(kind: ErrorKind) -> i32 {
+    match kind {
+        ErrorKind::Other => 1,
+        ErrorKind::NothingToPull => 2,
+        ErrorKind::InvalidConfig => 3,
+        ErrorKind::DirtyWorktree => 4,
+        ErrorKind::SyncState => 5,
+        ErrorKind::MergeConflict => 6,
+        ErrorKind::JoshUnavailable => 7,
+        ErrorKind::InvalidHistory => 8,
+        ErrorKind::ForkBranch => 9,
+        ErrorKind::CommandFailed => 10,
+    }
+}
+
+fn run(args: Args) -> anyhow::Result<()> {
     match args.cmd {
         Command::Init => {
             let config = JoshConfig {
                 org: "rust-lang".to_string(),
                 repo: "<repository-name>".to_string(),
+                upstream_repo: DEFAULT_UPSTREAM_REPO.to_string(),
+                upstream_branch: None,
+                forge: Forge::GitHub,
+                josh_port: None,
+                josh_startup_timeout: None,
+                use_global_git_config: false,
                 path: Some("<relative-subtree-path>".to_string()),
                 filter: None,
                 post_pull: vec![],
                 subtree_filter: None,
+                messages: MessagesConfig::default(),
+                pull_request: PullRequestConfig::default(),
+                subtrees: vec![],
             };
             config
                 .write(Path::new(DEFAULT_CONFIG_PATH))
@@ -108,136 +344,751 @@ fn main() -> anyhow::Result<()> {
             upstream_repo,
             upstream_commit,
             allow_noop,
+            dry_run,
+            continue_pull,
+            abort,
+            output,
             shared,
         } => {
-            let ctx = load_context(&shared.config_path, &shared.rust_version_path)?;
-            let josh = get_josh_proxy(shared.josh_proxy, shared.verbose)?;
-            let sync = GitSync::new(ctx.clone(), josh, shared.verbose);
-            match sync.rustc_pull(upstream_repo, upstream_commit, allow_noop) {
-                Ok(result) => {
-                    if !maybe_create_gh_pr(
-                        &ctx.config.full_repo_name(),
-                        "Rustc pull update",
-                        &result.merge_commit_message,
-                    )? {
-                        println!(
-                            "Now push the current branch to {} (either a fork or the main repo) and create a PR",
-                            ctx.config.repo
-                        );
+            let runner = SystemRunner::new(shared.verbose);
+            if abort {
+                abort_pull(&runner)?;
+                return Ok(());
+            }
+            let mut contexts = load_contexts(&shared)?;
+            if let Some(upstream_repo) = upstream_repo {
+                for ctx in &mut contexts {
+                    ctx.config.upstream_repo = upstream_repo.clone();
+                }
+            }
+            let config = contexts[0].config.clone();
+            let multiple = contexts.len() > 1;
+            let mut report = PullReport {
+                outcome: PullOutcome::NothingToPull,
+                subtrees: vec![],
+                error_kind: None,
+                error: None,
+            };
+            if continue_pull {
+                let result = sync::continue_pull(&contexts, &runner, &GixBackend::current_dir());
+                report.add(None, result);
+                if multiple && output == OutputFormat::Text {
+                    println!(
+                        "Run `rustc-josh-sync pull` again to pull the remaining subtrees, if any"
+                    );
+                }
+            } else {
+                let josh = get_josh_proxy(shared.josh_proxy, shared.verbose)?;
+                if dry_run {
+                    let previews = contexts
+                        .into_iter()
+                        .map(|ctx| {
+                            let name = ctx.subtree.name.clone();
+                            GitSync::new(ctx, josh.clone(), shared.verbose)
+                                .rustc_pull_preview(upstream_commit.clone())
+                                .with_context(|| {
+                                    format!("cannot perform dry-run pull of subtree `{name}`")
+                                })
+                        })
+                        .collect::<anyhow::Result<Vec<_>>>()?;
+                    match output {
+                        OutputFormat::Text => previews.iter().for_each(print_pull_preview),
+                        OutputFormat::Json => {
+                            println!("{}", serde_json::to_string_pretty(&previews)?)
+                        }
                     }
+                    return Ok(());
                 }
-                Err(RustcPullError::NothingToPull) => {
-                    eprintln!("Nothing to pull");
-                    if !allow_noop {
-                        std::process::exit(2);
+                for ctx in contexts {
+                    if multiple {
+                        eprintln!("Pulling subtree `{}`", ctx.subtree.name);
+                    }
+                    let sync = GitSync::new(ctx.clone(), josh.clone(), shared.verbose);
+                    let result = sync.rustc_pull(upstream_commit.clone(), allow_noop);
+                    if matches!(result, Err(Error::NothingToPull)) && multiple {
+                        eprintln!("Nothing to pull for subtree `{}`", ctx.subtree.name);
+                    }
+                    report.add(Some(ctx.subtree.name.clone()), result);
+                    if report.outcome == PullOutcome::Failed {
+                        break;
                     }
                 }
-                Err(RustcPullError::PullFailed(error)) => {
-                    eprintln!("Pull failure: {error:?}");
-                    if !shared.verbose {
-                        eprintln!("Rerun with `-v` to see executed commands");
+            }
+
+            let exit_code = match report.outcome {
+                PullOutcome::Failed => report
+                    .subtrees
+                    .iter()
+                    .find_map(|subtree| subtree.error_kind)
+                    .map_or(1, exit_code),
+                PullOutcome::NothingToPull if !allow_noop => exit_code(ErrorKind::NothingToPull),
+                _ => 0,
+            };
+            match output {
+                OutputFormat::Text => match report.outcome {
+                    PullOutcome::Failed => {
+                        for subtree in &report.subtrees {
+                            if let Some(error) = &subtree.error {
+                                eprintln!("Pull failure: {error}");
+                            }
+                        }
+                        if !shared.verbose {
+                            eprintln!("Rerun with `-v` to see executed commands");
+                        }
+                    }
+                    PullOutcome::NothingToPull => eprintln!("Nothing to pull"),
+                    PullOutcome::Pulled => {
+                        println!(
+                            "Now push the current branch to {} (either a fork or the main repo) and create a PR",
+                            config.repo
+                        );
+                        if config.forge.api_url().is_some() {
+                            println!(
+                                "You can create the PR using `rustc-josh-sync pull-pr --head <branch>`"
+                            );
+                        }
+                    }
+                },
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+            }
+            if exit_code != 0 {
+                std::process::exit(exit_code);
+            }
+        }
+        Command::PullPr {
+            head,
+            base,
+            author,
+            body,
+            config_path,
+            rust_version_path,
+            verbose,
+        } => {
+            let config = load_config(&config_path).context("cannot load config")?;
+            set_hermetic_git(!config.use_global_git_config);
+            let body = match body {
+                Some(body) => body,
+                None => {
+                    let messages = sync::latest_pull_merge_messages(
+                        &config,
+                        &rust_version_path,
+                        &SystemRunner::new(verbose),
+                    )
+                    .context("cannot find the merge commits of the latest pull")?;
+                    if messages.is_empty() {
+                        eprintln!(
+                            "No pull merge commit found on the current branch, using a generic description"
+                        );
+                        "Latest update from rustc.".to_string()
+                    } else {
+                        messages.join("\n\n")
                     }
-                    std::process::exit(1);
                 }
+            };
+            let repo = config.full_repo_name();
+            let title = render(
+                &config.messages.pull_pr_title,
+                &[("upstream_repo", &config.upstream_repo), ("repo", &repo)],
+            )?;
+            let client = GitHubClient::for_forge(&config.forge, verbose)?;
+            let outcome = client.create_or_update_pr(
+                &SyncPullRequest {
+                    repo: &repo,
+                    title: &title,
+                    body: &body,
+                    head: &head,
+                    base: base.as_deref(),
+                    author: author.as_deref(),
+                },
+                &config.pull_request,
+            )?;
+            match &outcome {
+                PullRequestOutcome::Created(_) => eprintln!("Created pull request"),
+                PullRequestOutcome::Updated(_) => eprintln!("Updated existing pull request"),
             }
+            println!("{}", outcome.pull_request().html_url);
         }
         Command::Push {
             username,
             branch,
+            dry_run,
+            update,
+            open_pr,
+            resume,
+            cleanup,
+            output,
             shared,
         } => {
-            let ctx = load_context(&shared.config_path, &shared.rust_version_path)?;
+            let runner = SystemRunner::new(shared.verbose);
+            if cleanup {
+                let config = load_config(&shared.config_path).context("cannot load config")?;
+                set_hermetic_git(!config.use_global_git_config);
+                cleanup_push(&config, &runner)?;
+                return Ok(());
+            }
+            let contexts = load_contexts(&shared)?;
+            let resumed_state = if resume {
+                Some(
+                    PushState::load(&runner)?
+                        .ok_or_else(|| anyhow::anyhow!("no push is in progress"))?,
+                )
+            } else {
+                None
+            };
+            let josh = get_josh_proxy(shared.josh_proxy, shared.verbose)?;
+            let mut state = if let Some(state) = resumed_state {
+                state
+            } else {
+                let (Some(branch), Some(username)) = (branch, username) else {
+                    unreachable!("clap requires the branch and the username");
+                };
+                let head = get_current_head_sha(&GixBackend::current_dir())?;
+                let multiple = contexts.len() > 1;
+                let subtrees = contexts
+                    .iter()
+                    .map(|ctx| SubtreePush {
+                        name: ctx.subtree.name.clone(),
+                        // Each subtree is pushed into its own branch
+                        branch: if multiple {
+                            format!("{branch}-{}", ctx.subtree.name)
+                        } else {
+                            branch.clone()
+                        },
+                        base_upstream_sha: ctx.last_upstream_sha.clone().unwrap_or_default(),
+                        head: head.clone(),
+                        stage: PushStage::FetchBase,
+                        update,
+                        previous_tip: None,
+                        rustc_git: None,
+                        open_pr,
+                    })
+                    .collect::<Vec<_>>();
+                if dry_run {
+                    let mut previews = vec![];
+                    for (ctx, push) in contexts.into_iter().zip(subtrees) {
+                        let preview = GitSync::new(ctx, josh.clone(), shared.verbose)
+                            .rustc_push_preview(&username, &push.branch)
+                            .with_context(|| {
+                                format!("cannot perform dry-run push of subtree `{}`", push.name)
+                            })?;
+                        if output == OutputFormat::Text {
+                            print_push_preview(&preview, &push.branch);
+                        }
+                        previews.push(preview);
+                    }
+                    if output == OutputFormat::Json {
+                        println!("{}", serde_json::to_string_pretty(&previews)?);
+                    }
+                    return Ok(());
+                }
+                PushState::start(&username, subtrees, &runner)?
+            };
+            let mut report = PushReport {
+                outcome: PushOutcome::Pushed,
+                subtrees: vec![],
+                error_kind: None,
+                error: None,
+            };
+            for push in state.subtrees.clone() {
+                if push.stage == PushStage::Done {
+                    continue;
+                }
+                let ctx = contexts
+                    .iter()
+                    .find(|ctx| ctx.subtree.name == push.name)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "subtree `{}` of the push in progress was not selected",
+                            push.name
+                        )
+                    })?;
+                let result = push_subtree(ctx.clone(), josh.clone(), &mut state, shared.verbose);
+                match result {
+                    Ok(subtree_report) => {
+                        if output == OutputFormat::Text {
+                            print_push_report(&subtree_report, ctx);
+                        }
+                        if subtree_report.outcome == PushOutcome::Updated {
+                            report.outcome = PushOutcome::Updated;
+                        }
+                        report.subtrees.push(subtree_report);
+                    }
+                    Err(error) if output == OutputFormat::Json => {
+                        report.outcome = PushOutcome::Failed;
+                        report.subtrees.push(SubtreePushReport {
+                            subtree: push.name.clone(),
+                            outcome: PushOutcome::Failed,
+                            branch: push.branch.clone(),
+                            fork: format!("{}/{}", state.username, ctx.config.upstream_repo_name()),
+                            pr_url: None,
+                            new_pr_url: None,
+                            error_kind: Some(error_kind(&error)),
+                            error: Some(format!("{error:?}")),
+                        });
+                        println!("{}", serde_json::to_string_pretty(&report)?);
+                        std::process::exit(exit_code(error_kind(&error)));
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+            state.finish(&runner)?;
+            if output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            }
+        }
+        Command::Status { output, shared } => {
+            let contexts = load_contexts(&shared)?;
             let josh = get_josh_proxy(shared.josh_proxy, shared.verbose)?;
-            let sync = GitSync::new(ctx.clone(), josh, shared.verbose);
-            if let Err(error) = sync
-                .rustc_push(&username, &branch)
-                .context("cannot perform push")
-            {
-                if !shared.verbose {
-                    eprintln!("Rerun with `-v` to see executed commands");
+            let statuses = contexts
+                .into_iter()
+                .map(|ctx| {
+                    let name = ctx.subtree.name.clone();
+                    GitSync::new(ctx, josh.clone(), shared.verbose)
+                        .status()
+                        .with_context(|| format!("cannot determine status of subtree `{name}`"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            match output {
+                OutputFormat::Text => {
+                    for status in statuses {
+                        println!("Subtree `{}`:", status.subtree);
+                        println!(
+                            "  last pulled upstream commit: {}",
+                            status.last_upstream_sha
+                        );
+                        println!("  latest upstream commit: {}", status.upstream_sha);
+                        println!("  upstream commits to pull: {}", status.upstream_commits);
+                        println!("  local commits to push: {}", status.local_commits);
+                        if let Some(days) = status.days_since_last_sync {
+                            println!("  days since last pull: {days}");
+                        }
+                    }
+                }
+                OutputFormat::Json => {
+                    println!("{}", serde_json::to_string_pretty(&statuses)?);
+                }
+            }
+        }
+        Command::InstallJosh { force, verbose } => {
+            for tool in [JoshTool::Proxy, JoshTool::Filter] {
+                let path = match tool.find(verbose) {
+                    Some(path) if !force => path,
+                    _ => tool.install(&SystemRunner::new(verbose))?,
+                };
+                println!(
+                    "{} {JOSH_VERSION} is available at {}",
+                    tool.binary_name(),
+                    path.display()
+                );
+            }
+        }
+        Command::CheckConfig {
+            config_path,
+            rust_version_path,
+            rustc_git,
+            verbose,
+        } => {
+            let problems = check_config(
+                &config_path,
+                &rust_version_path,
+                rustc_git.as_deref(),
+                verbose,
+            );
+            if !problems.is_empty() {
+                for problem in &problems {
+                    eprintln!("- {problem}");
+                }
+                return Err(anyhow::anyhow!(
+                    "found {} problem(s) in {}",
+                    problems.len(),
+                    config_path.display()
+                ));
+            }
+            println!("{} is valid", config_path.display());
+        }
+        Command::Doctor {
+            config_path,
+            rust_version_path,
+            rustc_git,
+            verbose,
+        } => {
+            // The checks need to see the full git config
+            set_hermetic_git(false);
+            let checks = run_checks(
+                &config_path,
+                &rust_version_path,
+                rustc_git.as_deref(),
+                &SystemRunner::new(verbose),
+                verbose,
+            );
+            for check in &checks {
+                let status = match check.status {
+                    CheckStatus::Ok => "ok",
+                    CheckStatus::Warning => "warning",
+                    CheckStatus::Error => "error",
+                };
+                println!("[{status}] {}: {}", check.name, check.message);
+                if let Some(fix) = &check.fix {
+                    println!("    fix: {fix}");
                 }
-                return Err(error);
             }
+            let errors = checks
+                .iter()
+                .filter(|check| check.status == CheckStatus::Error)
+                .count();
+            if errors > 0 {
+                return Err(anyhow::anyhow!("found {errors} problem(s)"));
+            }
+        }
+    }
 
-            // Open PR with `subtree update` title to silence the `no-merges` triagebot check
-            let title = format!("{} subtree update", ctx.config.repo);
-            let head = get_current_head_sha(shared.verbose)?;
+    Ok(())
+}
 
-            let merge_msg = format!(
-                r#"Subtree update of `{repo}` to https://github.com/{full_repo}/commit/{head}.
+/// Commands whose `--output json` report is printed also when they fail.
+#[derive(Clone, Copy)]
+enum JsonReport {
+    Pull,
+    Push,
+}
 
-Created using https://github.com/rust-lang/josh-sync.
+/// Outcome of a pull, reported by `--output json`.
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum PullOutcome {
+    Pulled,
+    NothingToPull,
+    Failed,
+}
 
-r? @ghost"#,
-                repo = ctx.config.repo,
-                full_repo = ctx.config.full_repo_name(),
-            );
+#[derive(serde::Serialize)]
+struct PullReport {
+    outcome: PullOutcome,
+    subtrees: Vec<SubtreePullReport>,
+    /// Kind of an error that has made the whole command fail.
+    error_kind: Option<ErrorKind>,
+    error: Option<String>,
+}
 
-            println!(
-                r#"You can create the rustc PR using the following URL:
-https://github.com/{DEFAULT_UPSTREAM_REPO}/compare/{username}:{branch}?quick_pull=1&title={}&body={}"#,
-                urlencoding::encode(&title),
-                urlencoding::encode(&merge_msg)
-            );
+#[derive(serde::Serialize)]
+struct SubtreePullReport {
+    /// Name of the subtree, unknown if `pull --continue` has failed.
+    subtree: Option<String>,
+    outcome: PullOutcome,
+    upstream_sha: Option<String>,
+    filtered_sha: Option<String>,
+    merge_commit: Option<String>,
+    post_pull_commits: Vec<String>,
+    error_kind: Option<ErrorKind>,
+    error: Option<String>,
+}
+
+impl PullReport {
+    /// Reports an error that has made the whole command fail.
+    fn failed(error: &anyhow::Error) -> Self {
+        Self {
+            outcome: PullOutcome::Failed,
+            subtrees: vec![],
+            error_kind: Some(error_kind(error)),
+            error: Some(format!("{error:?}")),
         }
     }
 
-    Ok(())
+    /// Records the result of a pull of a single subtree.
+    fn add(&mut self, subtree: Option<String>, result: Result<PullResult, Error>) {
+        let mut report = SubtreePullReport {
+            subtree,
+            outcome: PullOutcome::NothingToPull,
+            upstream_sha: None,
+            filtered_sha: None,
+            merge_commit: None,
+            post_pull_commits: vec![],
+            error_kind: None,
+            error: None,
+        };
+        match result {
+            Ok(result) => {
+                report.subtree = Some(result.subtree);
+                report.outcome = PullOutcome::Pulled;
+                report.upstream_sha = Some(result.upstream_sha);
+                report.filtered_sha = Some(result.filtered_sha);
+                report.merge_commit = result.merge_commit;
+                report.post_pull_commits = result.post_pull_commits;
+            }
+            Err(Error::NothingToPull) => {}
+            Err(error) => {
+                report.outcome = PullOutcome::Failed;
+                report.error_kind = Some(error.kind());
+                report.error = Some(format!("{:?}", anyhow::Error::new(error)));
+            }
+        }
+        // A failure takes precedence over successful pulls
+        if report.outcome == PullOutcome::Failed || self.outcome == PullOutcome::NothingToPull {
+            self.outcome = report.outcome;
+        }
+        self.subtrees.push(report);
+    }
 }
 
-fn load_context(config_path: &Path, rust_version_path: &Path) -> anyhow::Result<SyncContext> {
-    let config = load_config(&config_path)
-        .context("cannot load config. Run the `init` command to initialize it.")?;
-    let rust_version = std::fs::read_to_string(&rust_version_path)
-        .inspect_err(|err| eprintln!("Cannot load rust-version file: {err:?}"))
-        .map(|version| version.trim().to_string())
-        .map(Some)
-        .unwrap_or_default();
-    Ok(SyncContext {
-        config,
-        last_upstream_sha_path: rust_version_path.to_path_buf(),
-        last_upstream_sha: rust_version,
-    })
-}
-
-fn maybe_create_gh_pr(repo: &str, title: &str, description: &str) -> anyhow::Result<bool> {
-    if which::which("gh").is_ok()
-        && prompt(
-            &format!("Do you want to create a {repo} pull PR using the `gh` tool?"),
-            false,
-        )
+/// Outcome of a push, reported by `--output json`.
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum PushOutcome {
+    /// A new branch was pushed.
+    Pushed,
+    /// An existing branch was updated.
+    Updated,
+    Failed,
+}
+
+#[derive(serde::Serialize)]
+struct PushReport {
+    outcome: PushOutcome,
+    subtrees: Vec<SubtreePushReport>,
+    /// Kind of an error that has made the whole command fail.
+    error_kind: Option<ErrorKind>,
+    error: Option<String>,
+}
+
+impl PushReport {
+    /// Reports an error that has made the whole command fail.
+    fn failed(error: &anyhow::Error) -> Self {
+        Self {
+            outcome: PushOutcome::Failed,
+            subtrees: vec![],
+            error_kind: Some(error_kind(error)),
+            error: Some(format!("{error:?}")),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct SubtreePushReport {
+    subtree: String,
+    outcome: PushOutcome,
+    /// Branch of the fork to which the subtree was pushed.
+    branch: String,
+    /// `<username>/<repo>` of the fork.
+    fork: String,
+    /// URL of the pull request opened by `--open-pr`.
+    pr_url: Option<String>,
+    /// URL that opens a pre-filled pull request, if no pull request was opened.
+    new_pr_url: Option<String>,
+    error_kind: Option<ErrorKind>,
+    error: Option<String>,
+}
+
+fn print_pull_preview(preview: &PullPreview) {
+    println!("Subtree `{}`:", preview.subtree);
+    println!("  upstream commit: {}", preview.upstream_sha);
+    println!("  incoming ref: {}", preview.incoming_ref);
+    if preview.commits.is_empty() {
+        println!("  nothing to pull");
+        return;
+    }
+    println!("  {} commit(s) would be merged:", preview.commits.len());
+    for commit in &preview.commits {
+        println!("    {commit}");
+    }
+    println!("{}", preview.diffstat);
+    if preview.conflicts.is_empty() {
+        println!("  the merge would not conflict");
+    } else {
+        println!("  the merge would conflict in:");
+        for path in &preview.conflicts {
+            println!("    {path}");
+        }
+    }
+}
+
+fn print_push_preview(preview: &PushPreview, branch: &str) {
+    println!("Subtree `{}`:", preview.subtree);
+    println!("  upstream base: {}", preview.base_upstream_sha);
+    println!("  filtered base: {}", preview.filtered_base);
+    if preview.branch_exists {
+        println!("  warning: branch `{branch}` already exists in your fork");
+    }
+    if preview.commits.is_empty() {
+        println!("  nothing to push");
+        return;
+    }
+    println!("  {} commit(s) would be pushed:", preview.commits.len());
+    for commit in &preview.commits {
+        println!("    {commit}");
+    }
+    match &preview.upstream_path {
+        Some(path) => println!("  local changes (to be applied under `{path}` upstream):"),
+        None => println!("  local changes:"),
+    }
+    println!("{}", preview.diffstat);
+    println!(
+        "  HEAD is based on the filtered base, the round-trip through Josh is checked only by the push"
+    );
+}
+
+fn push_subtree(
+    ctx: SyncContext,
+    josh: JoshProxy,
+    state: &mut PushState,
+    verbose: bool,
+) -> anyhow::Result<SubtreePushReport> {
+    let sync = GitSync::new(ctx.clone(), josh, verbose);
+    if let Err(error) = sync
+        .rustc_push(state)
+        .with_context(|| format!("cannot perform push of subtree `{}`", ctx.subtree.name))
     {
-        std::process::Command::new("gh")
-            .args(&[
-                "pr",
-                "create",
-                "--title",
-                title,
-                "--body",
-                description,
-                "--repo",
-                repo,
-            ])
-            .spawn()?
-            .wait()?;
-        Ok(true)
+        eprintln!(
+            "Run `rustc-josh-sync push --resume` to retry the push from the failed step, \
+            or `rustc-josh-sync push --cleanup` to delete the partially pushed branch(es) \
+            (updated branches are restored instead)."
+        );
+        if !verbose {
+            eprintln!("Rerun with `-v` to see executed commands");
+        }
+        return Err(error);
+    }
+    let username = &state.username;
+    let push = state
+        .subtrees
+        .iter()
+        .find(|push| push.name == ctx.subtree.name)
+        .expect("pushed subtree is part of the push state");
+    let branch = &push.branch;
+    let fork = format!("{username}/{}", ctx.config.upstream_repo_name());
+    let mut report = SubtreePushReport {
+        subtree: ctx.subtree.name.clone(),
+        outcome: PushOutcome::Pushed,
+        branch: branch.clone(),
+        fork: fork.clone(),
+        pr_url: None,
+        new_pr_url: None,
+        error_kind: None,
+        error: None,
+    };
+    if push.update {
+        report.outcome = PushOutcome::Updated;
+        return Ok(report);
+    }
+
+    let head = get_current_head_sha(&GixBackend::current_dir())?;
+    let forge = &ctx.config.forge;
+    let full_repo = ctx.config.full_repo_name();
+    let upstream_repo = &ctx.config.upstream_repo;
+
+    let changelog =
+        Changelog::since_last_pull(&ctx.last_upstream_sha_path, &SystemRunner::new(verbose))
+            .context("cannot determine the changes since the last pull")?;
+    let head_url = forge
+        .commit_url(&full_repo, &head)
+        .unwrap_or_else(|| format!("{full_repo}@{head}"));
+    let variables = [
+        ("subtree", ctx.subtree.name.as_str()),
+        ("repo", &full_repo),
+        ("upstream_repo", upstream_repo),
+        ("head_sha", &head),
+        ("head_url", &head_url),
+        ("changelog", &changelog.to_markdown(&full_repo)),
+    ];
+    // By default, the PR has a `subtree update` title to silence the `no-merges` triagebot check
+    let title = render(&ctx.config.messages.push_pr_title, &variables)?;
+    let merge_msg = render(&ctx.config.messages.push_pr_body, &variables)?;
+
+    if push.open_pr {
+        match open_push_pr(
+            &ctx,
+            &format!("{username}:{branch}"),
+            &title,
+            &merge_msg,
+            verbose,
+        ) {
+            Ok(url) => {
+                report.pr_url = Some(url);
+                return Ok(report);
+            }
+            Err(error) => eprintln!("Cannot open the rustc PR: {error:?}"),
+        }
+    }
+
+    report.new_pr_url =
+        forge.new_pull_request_url(upstream_repo, &fork, branch, &title, &merge_msg);
+    Ok(report)
+}
+
+fn print_push_report(report: &SubtreePushReport, ctx: &SyncContext) {
+    let SubtreePushReport { branch, fork, .. } = report;
+    if report.outcome == PushOutcome::Updated {
+        println!("Branch `{branch}` of {fork} was updated");
+    } else if let Some(url) = &report.pr_url {
+        println!("Opened the rustc PR at {url}");
+    } else if let Some(url) = &report.new_pr_url {
+        println!(
+            r#"You can create the rustc PR using the following URL:
+{url}"#
+        );
     } else {
-        Ok(false)
+        println!(
+            "Now create a PR from branch `{branch}` of {fork} against {}",
+            ctx.config.upstream_repo
+        );
     }
 }
 
+/// Opens a pull request from `head` against the upstream repository, and returns its URL.
+fn open_push_pr(
+    ctx: &SyncContext,
+    head: &str,
+    title: &str,
+    body: &str,
+    verbose: bool,
+) -> anyhow::Result<String> {
+    let client = GitHubClient::for_forge(&ctx.config.forge, verbose)?;
+    let pr = client.create_pr(&SyncPullRequest {
+        repo: &ctx.config.upstream_repo,
+        title,
+        body,
+        head,
+        base: ctx.config.upstream_branch.as_deref(),
+        author: None,
+    })?;
+    Ok(pr.html_url)
+}
+
+/// Loads a sync context for each subtree selected by `--subtree`.
+fn load_contexts(shared: &SharedArgs) -> anyhow::Result<Vec<SyncContext>> {
+    let config = load_config(&shared.config_path)
+        .context("cannot load config. Run the `init` command to initialize it.")?;
+    set_hermetic_git(!config.use_global_git_config);
+    let subtrees = config.select_subtrees(&shared.subtrees)?;
+    Ok(subtrees
+        .into_iter()
+        .map(|subtree| {
+            let rust_version_path = subtree
+                .rust_version
+                .clone()
+                .unwrap_or_else(|| shared.rust_version_path.clone());
+            let rust_version = std::fs::read_to_string(&rust_version_path)
+                .inspect_err(|err| eprintln!("Cannot load rust-version file: {err:?}"))
+                .map(|version| version.trim().to_string())
+                .map(Some)
+                .unwrap_or_default();
+            SyncContext {
+                config: config.clone(),
+                subtree,
+                last_upstream_sha_path: rust_version_path,
+                last_upstream_sha: rust_version,
+            }
+        })
+        .collect())
+}
+
 fn get_josh_proxy(proxy_path: Option<PathBuf>, verbose: bool) -> anyhow::Result<JoshProxy> {
     match proxy_path {
         Some(path) => {
-            println!("Using josh-proxy binary from {}", path.display());
+            eprintln!("Using josh-proxy binary from {}", path.display());
             Ok(JoshProxy::from_path(path))
         }
-        None => {
-            println!("Updating/installing josh-proxy binary...");
-            match try_install_josh(verbose) {
-                Some(proxy) => Ok(proxy),
-                None => Err(anyhow::anyhow!("Could not install josh-proxy")),
-            }
-        }
+        None => Ok(JoshProxy::find_or_install(verbose)
+            .context("Could not install josh-proxy")
+            .map_err(Error::JoshUnavailable)?),
     }
 }
@@ -0,0 +1,404 @@
+//! Changelog of upstream changes brought in by a pull.
+//!
+//! Upstream commits are grouped by the pull request that merged them, which is determined from
+//! the messages of the merge commits created by bors (`Auto merge of #N`), by rollups
+//! (`Rollup merge of #N`) and by GitHub (`Merge pull request #N`).
+use crate::command::CommandRunner;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::path::Path;
+
+/// A commit from the filtered upstream history.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Commit {
+    pub sha: String,
+    pub parents: Vec<String>,
+    pub message: String,
+}
+
+impl Commit {
+    fn subject(&self) -> &str {
+        self.message.lines().next().unwrap_or_default()
+    }
+
+    /// Returns the number and the title of the pull request merged by this commit, if it is
+    /// a pull request merge.
+    fn merged_pull_request(&self) -> Option<(u64, String)> {
+        let subject = self.subject();
+        let rest = [
+            "Auto merge of #",
+            "Rollup merge of #",
+            "Merge pull request #",
+        ]
+        .iter()
+        .find_map(|prefix| subject.strip_prefix(prefix))?;
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let number = rest[..digits].parse().ok()?;
+        // The title of the pull request is the first paragraph after the subject
+        let title = self
+            .message
+            .lines()
+            .skip(1)
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .unwrap_or_default()
+            .to_string();
+        Some((number, title))
+    }
+}
+
+/// An upstream pull request, together with the commits that it brought in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PullRequest {
+    pub number: u64,
+    pub title: String,
+    /// Non-merge commits of the pull request, as `(sha, subject)`.
+    pub commits: Vec<(String, String)>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Changelog {
+    pub pull_requests: Vec<PullRequest>,
+    /// Non-merge commits that do not belong to any pull request, as `(sha, subject)`.
+    pub other_commits: Vec<(String, String)>,
+}
+
+impl Changelog {
+    /// Loads the commits of the given revision range, e.g. `HEAD..<incoming-ref>`.
+    pub fn load(range: &str, runner: &dyn CommandRunner) -> anyhow::Result<Self> {
+        let log = runner.run(&[
+            "git",
+            "log",
+            "--topo-order",
+            "--format=%H%x00%P%x00%B%x1e",
+            range,
+        ])?;
+        let commits = log
+            .split('\x1e')
+            .map(|entry| entry.trim_start_matches('\n'))
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let mut fields = entry.splitn(3, '\0');
+                Some(Commit {
+                    sha: fields.next()?.to_string(),
+                    parents: fields
+                        .next()?
+                        .split_whitespace()
+                        .map(|parent| parent.to_string())
+                        .collect(),
+                    message: fields.next()?.trim().to_string(),
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(Self::from_commits(&commits))
+    }
+
+    /// Groups `commits`, which are expected in topological order with the newest commit first,
+    /// by the pull request that merged them.
+    pub fn from_commits(commits: &[Commit]) -> Self {
+        let by_sha: HashMap<&str, &Commit> = commits.iter().map(|c| (c.sha.as_str(), c)).collect();
+        let reachable = |start: &str| -> HashSet<&str> {
+            let mut visited = HashSet::new();
+            let mut stack = vec![start];
+            while let Some(sha) = stack.pop() {
+                if let Some(commit) = by_sha.get(sha)
+                    && visited.insert(commit.sha.as_str())
+                {
+                    stack.extend(commit.parents.iter().map(|p| p.as_str()));
+                }
+            }
+            visited
+        };
+
+        let mut assigned = HashSet::new();
+        let mut pull_requests = vec![];
+        // Process the oldest merges first, so that the commits of pull requests that were
+        // merged in a rollup are assigned to them, not to the rollup.
+        for commit in commits.iter().rev() {
+            let Some((number, title)) = commit.merged_pull_request() else {
+                continue;
+            };
+            let [first_parent, merged_parent] = commit.parents.as_slice() else {
+                continue;
+            };
+            // Rollups are represented by the pull requests that they contain
+            if title.starts_with("Rollup of ") {
+                continue;
+            }
+            let base = reachable(first_parent);
+            let merged = reachable(merged_parent);
+            let mut pr_commits = vec![];
+            // Iterate in the original order, to keep the commits sorted
+            for candidate in commits {
+                let sha = candidate.sha.as_str();
+                if merged.contains(sha)
+                    && !base.contains(sha)
+                    && assigned.insert(sha)
+                    && candidate.parents.len() < 2
+                {
+                    pr_commits.push((candidate.sha.clone(), candidate.subject().to_string()));
+                }
+            }
+            // The commits of the pull request may have been brought in by earlier merges
+            if pr_commits.is_empty() {
+                continue;
+            }
+            pull_requests.push(PullRequest {
+                number,
+                title,
+                commits: pr_commits,
+            });
+        }
+        pull_requests.reverse();
+
+        let other_commits = commits
+            .iter()
+            .filter(|c| c.parents.len() < 2 && !assigned.contains(c.sha.as_str()))
+            .map(|c| (c.sha.clone(), c.subject().to_string()))
+            .collect();
+        Self {
+            pull_requests,
+            other_commits,
+        }
+    }
+
+    /// Loads the local commits made since the last pull was merged. The changelog is empty if
+    /// no pull has been merged yet.
+    ///
+    /// The pull merge is found by its structure, not by its message: its first parent is the
+    /// commit that last updated the `rust-version` file at `rust_version_path`.
+    pub fn since_last_pull(
+        rust_version_path: &Path,
+        runner: &dyn CommandRunner,
+    ) -> anyhow::Result<Self> {
+        let rust_version_path = rust_version_path.to_string_lossy();
+        let prepare_commit =
+            runner.run(&["git", "log", "-1", "--format=%H", "--", &rust_version_path])?;
+        if prepare_commit.is_empty() {
+            return Ok(Self::default());
+        }
+        // The pull merge may have reached HEAD through other merges (e.g. of a pull request),
+        // so it is not necessarily on the first-parent chain.
+        let merges = runner.run(&[
+            "git",
+            "rev-list",
+            "--merges",
+            "--parents",
+            "--ancestry-path",
+            &format!("{prepare_commit}..HEAD"),
+        ])?;
+        let last_pull = merges.lines().find_map(|line| {
+            let mut shas = line.split_whitespace();
+            let merge = shas.next()?;
+            (shas.next()? == prepare_commit).then_some(merge)
+        });
+        match last_pull {
+            Some(last_pull) => Self::load(&format!("{last_pull}..HEAD"), runner),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pull_requests.is_empty() && self.other_commits.is_empty()
+    }
+
+    /// Renders the changelog as a Markdown list, referring to pull requests of `repo`.
+    /// Bare `#123` references in titles and subjects are rewritten to `repo#123`, so that they
+    /// point to the right repository when the changelog is shown in a different one.
+    pub fn to_markdown(&self, repo: &str) -> String {
+        let mut out = String::new();
+        for pr in &self.pull_requests {
+            let title = qualify_references(&pr.title, repo);
+            writeln!(out, "- {repo}#{}: {title}", pr.number).unwrap();
+            for (sha, subject) in &pr.commits {
+                let subject = qualify_references(subject, repo);
+                writeln!(out, "  - {} {subject}", short_sha(sha)).unwrap();
+            }
+        }
+        if !self.other_commits.is_empty() {
+            writeln!(out, "- Other commits:").unwrap();
+            for (sha, subject) in &self.other_commits {
+                let subject = qualify_references(subject, repo);
+                writeln!(out, "  - {} {subject}", short_sha(sha)).unwrap();
+            }
+        }
+        out
+    }
+}
+
+/// Rewrites bare `#123` issue references in `text` to `repo#123`.
+/// References that are already qualified (`org/repo#123`) are kept as-is.
+pub fn qualify_references(text: &str, repo: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut prev = None;
+    for (index, c) in text.char_indices() {
+        let is_reference = c == '#'
+            && !prev
+                .is_some_and(|p: char| p.is_alphanumeric() || matches!(p, '/' | '&' | '_' | '-'))
+            && text[index + 1..].starts_with(|c: char| c.is_ascii_digit());
+        if is_reference {
+            out.push_str(repo);
+        }
+        out.push(c);
+        prev = Some(c);
+    }
+    out
+}
+
+fn short_sha(sha: &str) -> &str {
+    &sha[..sha.len().min(12)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::ScriptedRunner;
+
+    fn commit(sha: &str, parents: &[&str], message: &str) -> Commit {
+        Commit {
+            sha: sha.to_string(),
+            parents: parents.iter().map(|p| p.to_string()).collect(),
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_pull_request_merges() {
+        let parse = |message: &str| commit("a", &["b", "c"], message).merged_pull_request();
+        assert_eq!(
+            parse("Auto merge of #123 - user:branch, r=reviewer\n\nFix the thing\n\nDetails"),
+            Some((123, "Fix the thing".to_string()))
+        );
+        assert_eq!(
+            parse("Rollup merge of #45 - user:branch, r=reviewer\n\nAdd a feature"),
+            Some((45, "Add a feature".to_string()))
+        );
+        assert_eq!(
+            parse("Merge pull request #7 from user/branch\n\nUpdate docs"),
+            Some((7, "Update docs".to_string()))
+        );
+        assert_eq!(parse("Merge ref 'abc' from rust-lang/rust"), None);
+        assert_eq!(parse("Auto merge of #abc"), None);
+    }
+
+    #[test]
+    fn group_commits_by_pull_request() {
+        // base <- c1 <- r1 (rollup merge of #2) <- m1 (auto merge of rollup)
+        //    \               /
+        //     c2 (in #2) ---
+        // m1 <- c3 (direct push) <- m2 (auto merge of #3, with c4)
+        let commits = [
+            commit(
+                "m2",
+                &["c3", "c4"],
+                "Auto merge of #3 - a:b, r=c\n\nImprove performance",
+            ),
+            commit("c4", &["m1"], "Make it faster"),
+            commit("c3", &["m1"], "Direct commit"),
+            commit(
+                "m1",
+                &["base", "r1"],
+                "Auto merge of #10 - rollup, r=c\n\nRollup of 2 pull requests",
+            ),
+            commit(
+                "r1",
+                &["c1", "c2"],
+                "Rollup merge of #2 - a:b, r=c\n\nFix a bug",
+            ),
+            commit("c2", &["base"], "Fix the bug"),
+            commit("c1", &["base"], "Commit in the rollup"),
+        ];
+        let changelog = Changelog::from_commits(&commits);
+        assert_eq!(
+            changelog.to_markdown("rust-lang/rust"),
+            "- rust-lang/rust#3: Improve performance\n  \
+             - c4 Make it faster\n\
+             - rust-lang/rust#2: Fix a bug\n  \
+             - c2 Fix the bug\n\
+             - Other commits:\n  \
+             - c3 Direct commit\n  \
+             - c1 Commit in the rollup\n"
+        );
+    }
+
+    #[test]
+    fn skip_pull_requests_without_commits() {
+        // #2 only merges commits that are already part of the base
+        let commits = [
+            commit(
+                "m2",
+                &["m1", "c1"],
+                "Merge pull request #2 from a/b\n\nRe-merge",
+            ),
+            commit(
+                "m1",
+                &["base", "c1"],
+                "Auto merge of #1 - a:b, r=c\n\nAdd a feature",
+            ),
+            commit("c1", &["base"], "Implement the feature"),
+        ];
+        assert_eq!(
+            Changelog::from_commits(&commits).to_markdown("rust-lang/rust"),
+            "- rust-lang/rust#1: Add a feature\n  - c1 Implement the feature\n"
+        );
+    }
+
+    #[test]
+    fn qualify_issue_references() {
+        let qualify = |text: &str| qualify_references(text, "rust-lang/miri");
+        assert_eq!(qualify("Fix #12"), "Fix rust-lang/miri#12");
+        assert_eq!(
+            qualify("#1, (#2) and #3."),
+            "rust-lang/miri#1, (rust-lang/miri#2) and rust-lang/miri#3."
+        );
+        assert_eq!(qualify("See rust-lang/rust#4"), "See rust-lang/rust#4");
+        assert_eq!(qualify("issue#5 &#123; #abc #"), "issue#5 &#123; #abc #");
+    }
+
+    #[test]
+    fn changes_since_last_pull() {
+        const LOG: &str = "git log --topo-order --format=%H%x00%P%x00%B%x1e";
+        // prepare <- pull (merge of upstream, with a custom message) <- pr (merge of the pull
+        // into the default branch, whose first parent is a local commit) <- local
+        let runner = ScriptedRunner::new()
+            .expect("git log -1 --format=%H -- rust-version", "prepare")
+            .expect(
+                "git rev-list --merges --parents --ancestry-path prepare..HEAD",
+                "pr main pull\npull prepare upstream",
+            )
+            .expect(
+                &format!("{LOG} pull..HEAD"),
+                "local\0pr\0Fix the build\x1e\n\
+                 pr\0main pull\0Merge pull request #9 from user/pull\n\nSync with rustc\x1e\n\
+                 main\0base\0Local change\x1e\n",
+            );
+        let changelog = Changelog::since_last_pull(Path::new("rust-version"), &runner).unwrap();
+        assert_eq!(
+            changelog.to_markdown("rust-lang/miri"),
+            "- Other commits:\n  \
+             - local Fix the build\n  \
+             - main Local change\n"
+        );
+        assert!(runner.is_done());
+
+        // Without a previous pull, the changelog is empty, instead of containing all commits
+        let runner = ScriptedRunner::new()
+            .expect("git log -1 --format=%H -- rust-version", "init")
+            .expect(
+                "git rev-list --merges --parents --ancestry-path init..HEAD",
+                "",
+            );
+        let changelog = Changelog::since_last_pull(Path::new("rust-version"), &runner).unwrap();
+        assert!(changelog.is_empty());
+        assert!(runner.is_done());
+    }
+
+    #[test]
+    fn empty_changelog() {
+        let changelog = Changelog::from_commits(&[]);
+        assert!(changelog.is_empty());
+        assert_eq!(changelog.to_markdown("rust-lang/rust"), "");
+    }
+}
@@ -0,0 +1,179 @@
+//! Execution of external commands, mostly git.
+//!
+//! The sync logic runs its commands through a [`CommandRunner`], so that it can be tested with
+//! a [`ScriptedRunner`] instead of real repositories.
+use crate::error::Error;
+use crate::utils::{run_command, run_command_at, run_command_with_exit_code, stream_command};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Runs external commands. `args[0]` is the program, the rest are its arguments.
+pub trait CommandRunner: Send + Sync {
+    /// Runs a command in `workdir` (the current directory if `None`) and returns its stdout.
+    fn run_at(&self, args: &[&str], workdir: Option<&Path>) -> anyhow::Result<String>;
+
+    /// Runs a command while streaming its output to stderr.
+    fn stream(&self, args: &[&str]) -> anyhow::Result<()>;
+
+    /// Runs a command and returns its exit code and stdout, without treating a non-zero exit
+    /// code as an error.
+    fn run_with_exit_code(&self, args: &[&str]) -> anyhow::Result<(Option<i32>, String)>;
+
+    /// Runs a command in the current directory and returns its stdout.
+    fn run(&self, args: &[&str]) -> anyhow::Result<String> {
+        self.run_at(args, None)
+    }
+}
+
+/// Spawns real processes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemRunner {
+    verbose: bool,
+}
+
+impl SystemRunner {
+    pub fn new(verbose: bool) -> Self {
+        Self { verbose }
+    }
+}
+
+impl CommandRunner for SystemRunner {
+    fn run_at(&self, args: &[&str], workdir: Option<&Path>) -> anyhow::Result<String> {
+        match workdir {
+            Some(workdir) => run_command_at(args, workdir, self.verbose),
+            None => run_command(args, self.verbose),
+        }
+    }
+
+    fn stream(&self, args: &[&str]) -> anyhow::Result<()> {
+        stream_command(args, self.verbose)
+    }
+
+    fn run_with_exit_code(&self, args: &[&str]) -> anyhow::Result<(Option<i32>, String)> {
+        run_command_with_exit_code(args, self.verbose)
+    }
+}
+
+/// A fake runner for tests, which records the executed commands and answers them with
+/// scripted outputs.
+///
+/// Commands are identified by their arguments joined with spaces. Every scripted output is
+/// used once, in the order in which it was added. Commands without a scripted output fail.
+#[derive(Debug, Default)]
+pub struct ScriptedRunner {
+    outputs: Mutex<VecDeque<(String, ScriptedOutput)>>,
+    calls: Mutex<Vec<String>>,
+}
+
+#[derive(Debug)]
+struct ScriptedOutput {
+    code: i32,
+    stdout: String,
+}
+
+impl ScriptedRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes the next execution of `command` succeed with the given stdout.
+    pub fn expect(self, command: &str, stdout: &str) -> Self {
+        self.expect_exit_code(command, 0, stdout)
+    }
+
+    /// Makes the next execution of `command` fail with exit code 1.
+    pub fn expect_failure(self, command: &str) -> Self {
+        self.expect_exit_code(command, 1, "")
+    }
+
+    /// Makes the next execution of `command` exit with the given code and stdout.
+    pub fn expect_exit_code(self, command: &str, code: i32, stdout: &str) -> Self {
+        self.outputs.lock().unwrap().push_back((
+            command.to_string(),
+            ScriptedOutput {
+                code,
+                stdout: stdout.to_string(),
+            },
+        ));
+        self
+    }
+
+    /// Commands that were executed so far, in order.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Returns true if all scripted outputs were used.
+    pub fn is_done(&self) -> bool {
+        self.outputs.lock().unwrap().is_empty()
+    }
+
+    fn execute(&self, args: &[&str]) -> anyhow::Result<(Option<i32>, String)> {
+        let command = args.join(" ");
+        self.calls.lock().unwrap().push(command.clone());
+        let mut outputs = self.outputs.lock().unwrap();
+        let index = outputs
+            .iter()
+            .position(|(scripted, _)| *scripted == command)
+            .ok_or_else(|| anyhow::anyhow!("command `{command}` was not scripted"))?;
+        let (_, output) = outputs.remove(index).unwrap();
+        Ok((Some(output.code), output.stdout))
+    }
+}
+
+impl CommandRunner for ScriptedRunner {
+    fn run_at(&self, args: &[&str], _workdir: Option<&Path>) -> anyhow::Result<String> {
+        let (code, stdout) = self.execute(args)?;
+        if code != Some(0) {
+            return Err(Error::CommandFailed {
+                command: args.join(" "),
+                code,
+                stdout,
+                stderr: String::new(),
+            }
+            .into());
+        }
+        Ok(stdout)
+    }
+
+    fn stream(&self, args: &[&str]) -> anyhow::Result<()> {
+        self.run(args).map(|_| ())
+    }
+
+    fn run_with_exit_code(&self, args: &[&str]) -> anyhow::Result<(Option<i32>, String)> {
+        self.execute(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripted_outputs() {
+        let runner = ScriptedRunner::new()
+            .expect("git rev-parse HEAD", "a")
+            .expect_failure("git diff --exit-code a")
+            .expect("git rev-parse HEAD", "b");
+        assert_eq!(runner.run(&["git", "rev-parse", "HEAD"]).unwrap(), "a");
+        assert_eq!(runner.run(&["git", "rev-parse", "HEAD"]).unwrap(), "b");
+        assert_eq!(
+            runner
+                .run_with_exit_code(&["git", "diff", "--exit-code", "a"])
+                .unwrap(),
+            (Some(1), String::new())
+        );
+        assert!(runner.run(&["git", "status"]).is_err());
+        assert!(runner.is_done());
+        assert_eq!(
+            runner.calls(),
+            [
+                "git rev-parse HEAD",
+                "git rev-parse HEAD",
+                "git diff --exit-code a",
+                "git status"
+            ]
+        );
+    }
+}
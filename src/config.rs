@@ -1,5 +1,10 @@
+use crate::error::Error;
+use crate::filter::{Filter, Op};
+use crate::forge::Forge;
+use crate::template::check_template;
+use crate::utils::{is_full_sha, run_command_at};
 use anyhow::Context;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 #[serde(rename_all = "kebab-case")]
@@ -7,6 +12,29 @@ pub struct JoshConfig {
     #[serde(default = "default_org")]
     pub org: String,
     pub repo: String,
+    /// Upstream repository with which the subtree is synchronized.
+    #[serde(default = "default_upstream_repo")]
+    pub upstream_repo: String,
+    /// Branch of the upstream repository that is pulled from.
+    /// If not set, the default branch of the upstream repository is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_branch: Option<String>,
+    /// Service hosting the upstream repository, its forks and the subtree repository.
+    #[serde(default, skip_serializing_if = "Forge::is_github")]
+    pub forge: Forge,
+    /// Port on which `josh-proxy` should listen.
+    /// If not set, a free port is chosen automatically, so that multiple syncs can run
+    /// concurrently.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub josh_port: Option<u16>,
+    /// How long to wait for `josh-proxy` to start, in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub josh_startup_timeout: Option<u64>,
+    /// Use the full global and system git config for git commands.
+    /// By default, only the user identity, credential helpers and URL rewrites are taken from
+    /// them, so that settings like `fetch.prunetags` cannot change the outcome of a sync.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub use_global_git_config: bool,
     /// Relative path where the subtree is located in rust-lang/rust.
     /// For example `src/doc/rustc-dev-guide`.
     pub path: Option<String>,
@@ -20,6 +48,42 @@ pub struct JoshConfig {
     /// Optional subtree filter applied to the local `HEAD` during round-trip check.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub subtree_filter: Option<String>,
+    /// Templates of the commit messages and pull request texts created by josh-sync.
+    #[serde(default, skip_serializing_if = "MessagesConfig::is_default")]
+    pub messages: MessagesConfig,
+    /// Labels and reviewers of the pull requests created by josh-sync.
+    #[serde(default, skip_serializing_if = "PullRequestConfig::is_empty")]
+    pub pull_request: PullRequestConfig,
+    /// Subtrees synchronized by this repository, if it mirrors more than one directory
+    /// of rust-lang/rust.
+    /// It cannot be used together with the top-level `path`, `filter`, `post-pull`
+    /// and `subtree-filter` fields.
+    #[serde(default, rename = "subtree", skip_serializing_if = "Vec::is_empty")]
+    pub subtrees: Vec<SubtreeConfig>,
+}
+
+/// A single subtree synchronized with rust-lang/rust.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct SubtreeConfig {
+    /// Name used to select the subtree on the command line.
+    pub name: String,
+    /// Relative path where the subtree is located in rust-lang/rust.
+    pub path: Option<String>,
+    /// Optional filter specification for Josh.
+    /// It cannot be used together with `path`.
+    pub filter: Option<String>,
+    /// Path to a file storing the last synchronized rustc commit of this subtree.
+    /// Required for `[[subtree]]` entries, the top-level subtree uses the
+    /// `--rust-version-path` command line argument instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rust_version: Option<PathBuf>,
+    /// Operation(s) that should be performed after a pull of this subtree.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post_pull: Vec<PostPullOperation>,
+    /// Optional subtree filter applied to the local `HEAD` during round-trip check.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subtree_filter: Option<String>,
 }
 
 /// Execute an operation after a pull, and if something changes in the local git state,
@@ -36,21 +100,239 @@ pub struct PostPullOperation {
     pub commit_message: String,
 }
 
+/// Settings of pull requests created through the GitHub API.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PullRequestConfig {
+    /// Labels added to the pull request.
+    pub labels: Vec<String>,
+    /// GitHub users (or `<org>/<team>` teams) requested to review a new pull request.
+    pub reviewers: Vec<String>,
+}
+
+impl PullRequestConfig {
+    fn is_empty(&self) -> bool {
+        self.labels.is_empty() && self.reviewers.is_empty()
+    }
+}
+
+/// Templates of commit messages and pull request texts, see the `template` module for their
+/// syntax. Each template has its own set of available variables.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct MessagesConfig {
+    /// Message of the commit that updates the `rust-version` file before a pull.
+    pub prep_commit: String,
+    /// Message of the merge commit created by a pull.
+    pub merge_commit: String,
+    /// Title of the pull request created in the subtree repository after a pull.
+    pub pull_pr_title: String,
+    /// Title of the pull request created in the upstream repository after a push.
+    pub push_pr_title: String,
+    /// Description of the pull request created in the upstream repository after a push.
+    pub push_pr_body: String,
+}
+
+impl MessagesConfig {
+    pub const PREP_COMMIT_VARIABLES: &[&str] = &["upstream_repo", "upstream_sha", "previous_sha"];
+    pub const MERGE_COMMIT_VARIABLES: &[&str] = &[
+        "upstream_repo",
+        "upstream_url",
+        "upstream_sha",
+        "upstream_sha_short",
+        "previous_sha",
+        "upstream_diff_url",
+        "filtered_sha",
+        "repo",
+        "changelog",
+    ];
+    pub const PULL_PR_TITLE_VARIABLES: &[&str] = &["upstream_repo", "repo"];
+    pub const PUSH_PR_VARIABLES: &[&str] = &[
+        "subtree",
+        "repo",
+        "upstream_repo",
+        "head_sha",
+        "head_url",
+        "changelog",
+    ];
+
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn validate(&self) -> Vec<String> {
+        [
+            (
+                "prep-commit",
+                &self.prep_commit,
+                Self::PREP_COMMIT_VARIABLES,
+            ),
+            (
+                "merge-commit",
+                &self.merge_commit,
+                Self::MERGE_COMMIT_VARIABLES,
+            ),
+            (
+                "pull-pr-title",
+                &self.pull_pr_title,
+                Self::PULL_PR_TITLE_VARIABLES,
+            ),
+            (
+                "push-pr-title",
+                &self.push_pr_title,
+                Self::PUSH_PR_VARIABLES,
+            ),
+            ("push-pr-body", &self.push_pr_body, Self::PUSH_PR_VARIABLES),
+        ]
+        .into_iter()
+        .filter_map(|(name, template, variables)| {
+            check_template(template, variables)
+                .err()
+                .map(|error| format!("Invalid `messages.{name}` template: {error}"))
+        })
+        .collect()
+    }
+}
+
+impl Default for MessagesConfig {
+    fn default() -> Self {
+        Self {
+            prep_commit: r#"Prepare for merging from {{upstream_repo}}
+
+This updates the rust-version file to {{upstream_sha}}."#
+                .to_string(),
+            merge_commit: r#"Merge ref '{{upstream_sha_short}}' from {{upstream_repo}}
+
+Pull recent changes from {{upstream_url}} via Josh.
+
+Upstream ref: {{upstream_repo}}@{{upstream_sha}}
+Filtered ref: {{repo}}@{{filtered_sha}}{{#if upstream_diff_url}}
+Upstream diff: {{upstream_diff_url}}{{/if}}
+{{#if changelog}}
+Upstream changes:
+
+{{changelog}}{{/if}}
+This merge was created using https://github.com/rust-lang/josh-sync.
+"#
+            .to_string(),
+            pull_pr_title: "Rustc pull update".to_string(),
+            push_pr_title: "{{subtree}} subtree update".to_string(),
+            push_pr_body: r#"Subtree update of `{{subtree}}` to {{head_url}}.
+{{#if changelog}}
+Changes:
+
+{{changelog}}{{/if}}
+Created using https://github.com/rust-lang/josh-sync.
+
+r? @ghost"#
+                .to_string(),
+        }
+    }
+}
+
 impl JoshConfig {
     pub fn full_repo_name(&self) -> String {
         format!("{}/{}", self.org, self.repo)
     }
 
-    pub fn construct_josh_filter(&self) -> String {
-        let filter = match (&self.path, &self.filter) {
-            (Some(path), None) => format!(":/{path}"),
-            (None, Some(filter)) => filter.clone(),
-            _ => unreachable!("Config contains both path and a filter"),
-        };
+    /// Returns the git ref of the upstream branch that should be pulled from.
+    pub fn upstream_ref(&self) -> String {
+        match &self.upstream_branch {
+            Some(branch) => format!("refs/heads/{branch}"),
+            None => "HEAD".to_string(),
+        }
+    }
 
-        let filter = convert_rev_syntax(&filter);
-        let filter = wrap_compat(&filter);
-        filter
+    /// Returns the name of the upstream repository without its organization,
+    /// which is also the name of user forks of the upstream repository.
+    pub fn upstream_repo_name(&self) -> &str {
+        self.upstream_repo
+            .rsplit_once('/')
+            .map(|(_, name)| name)
+            .unwrap_or(&self.upstream_repo)
+    }
+
+    /// Returns all subtrees described by this config.
+    /// A config without `[[subtree]]` entries describes a single subtree named after the
+    /// repository.
+    pub fn subtrees(&self) -> Vec<SubtreeConfig> {
+        if !self.subtrees.is_empty() {
+            return self.subtrees.clone();
+        }
+        vec![SubtreeConfig {
+            name: self.repo.clone(),
+            path: self.path.clone(),
+            filter: self.filter.clone(),
+            rust_version: None,
+            post_pull: self.post_pull.clone(),
+            subtree_filter: self.subtree_filter.clone(),
+        }]
+    }
+
+    /// Returns the subtrees with the given names, or all subtrees if `names` is empty.
+    pub fn select_subtrees(&self, names: &[String]) -> anyhow::Result<Vec<SubtreeConfig>> {
+        let subtrees = self.subtrees();
+        if names.is_empty() {
+            return Ok(subtrees);
+        }
+        names
+            .iter()
+            .map(|name| {
+                subtrees
+                    .iter()
+                    .find(|subtree| &subtree.name == name)
+                    .cloned()
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "Unknown subtree `{name}`. Available subtrees: {}",
+                            subtrees
+                                .iter()
+                                .map(|s| s.name.as_str())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    /// Checks that the config is well-formed, and returns all problems that were found.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = self.messages.validate();
+        if self.subtrees.is_empty() {
+            problems.extend(check_path_and_filter(&self.path, &self.filter));
+        } else {
+            if self.path.is_some()
+                || self.filter.is_some()
+                || !self.post_pull.is_empty()
+                || self.subtree_filter.is_some()
+            {
+                problems.push(
+                    "Cannot combine top-level `path`, `filter`, `post-pull` or `subtree-filter` with `[[subtree]]` entries".to_string(),
+                );
+            }
+            for (index, subtree) in self.subtrees.iter().enumerate() {
+                if let Some(problem) = check_path_and_filter(&subtree.path, &subtree.filter) {
+                    problems.push(format!("Subtree `{}`: {problem}", subtree.name));
+                }
+                if subtree.rust_version.is_none() {
+                    problems.push(format!(
+                        "Subtree `{}` must specify a `rust-version` path",
+                        subtree.name
+                    ));
+                }
+                if self.subtrees[..index]
+                    .iter()
+                    .any(|other| other.name == subtree.name)
+                {
+                    problems.push(format!("Duplicate subtree name `{}`", subtree.name));
+                }
+            }
+        }
+        for subtree in self.subtrees() {
+            problems.extend(subtree.validate());
+        }
+        problems
     }
 
     pub fn write(&self, path: &Path) -> anyhow::Result<()> {
@@ -60,63 +342,200 @@ impl JoshConfig {
     }
 }
 
+impl SubtreeConfig {
+    pub fn construct_josh_filter(&self) -> anyhow::Result<String> {
+        let filter = match (&self.path, &self.filter) {
+            (Some(path), None) => format!(":/{path}"),
+            (None, Some(filter)) => filter.clone(),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "subtree `{}` must specify exactly one of `path` and `filter`",
+                    self.name
+                ));
+            }
+        };
+
+        let mut filter = Filter::parse(&filter)
+            .with_context(|| format!("invalid filter of subtree `{}`", self.name))?;
+        filter.convert_rev_syntax();
+        Ok(filter.wrap_compat().to_string())
+    }
+
+    /// Checks that the filters and post-pull operations of the subtree are well-formed.
+    fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.path.is_some() != self.filter.is_some()
+            && let Err(error) = self.construct_josh_filter()
+        {
+            problems.push(format!("{error:#}"));
+        }
+        if let Some(subtree_filter) = &self.subtree_filter
+            && let Err(error) = Filter::parse(subtree_filter)
+        {
+            problems.push(format!(
+                "invalid subtree-filter of subtree `{}`: {error}",
+                self.name
+            ));
+        }
+        for (index, op) in self.post_pull.iter().enumerate() {
+            if op.cmd.first().is_none_or(|cmd| cmd.is_empty()) {
+                problems.push(format!(
+                    "Subtree `{}`: `post-pull` operation #{} has an empty `cmd`",
+                    self.name,
+                    index + 1
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Checks that the `subtree-filter` adds the same prefix as the `filter`, so that
+    /// the round-trip check compares the same trees.
+    fn check_subtree_filter_consistency(&self) -> Option<String> {
+        let subtree_filter = Filter::parse(self.subtree_filter.as_ref()?).ok()?;
+        let filter = match (&self.path, &self.filter) {
+            (Some(path), None) => Filter::parse(&format!(":/{path}")).ok()?,
+            (None, Some(filter)) => Filter::parse(filter).ok()?,
+            _ => return None,
+        };
+        let prefix = filter.trailing_prefix();
+        if prefix == subtree_filter.trailing_prefix() {
+            return None;
+        }
+        Some(match prefix {
+            Some(prefix) => format!(
+                "Subtree `{}`: `filter` ends with `:prefix={prefix}`, so `subtree-filter` should end with it too",
+                self.name
+            ),
+            None => format!(
+                "Subtree `{}`: `subtree-filter` adds a prefix, but `filter` does not",
+                self.name
+            ),
+        })
+    }
+
+    /// Returns the path of the subtree in the upstream repository, if it can be determined.
+    pub fn upstream_path(&self) -> Option<String> {
+        if let Some(path) = &self.path {
+            return Some(path.clone());
+        }
+        Filter::parse(self.filter.as_ref()?)
+            .ok()?
+            .ops
+            .into_iter()
+            .find_map(|op| match op {
+                Op::Subdir(path) => Some(path),
+                _ => None,
+            })
+    }
+}
+
+pub const DEFAULT_UPSTREAM_REPO: &str = "rust-lang/rust";
+
 fn default_org() -> String {
     String::from("rust-lang")
 }
 
-pub fn load_config(path: &Path) -> anyhow::Result<JoshConfig> {
+fn default_upstream_repo() -> String {
+    String::from(DEFAULT_UPSTREAM_REPO)
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+pub fn load_config(path: &Path) -> crate::error::Result<JoshConfig> {
     let data = std::fs::read_to_string(path)
-        .with_context(|| format!("cannot load config file from {}", path.display()))?;
-    let config: JoshConfig = toml::from_str(&data).context("cannot load config as TOML")?;
-    if config.path.is_some() == config.filter.is_some() {
-        return if config.path.is_some() {
-            Err(anyhow::anyhow!("Cannot specify both `path` and `filter`"))
-        } else {
-            Err(anyhow::anyhow!("Must specify one of `path` and `filter`"))
-        };
-    }
+        .with_context(|| format!("cannot load config file from {}", path.display()))
+        .map_err(Error::InvalidConfig)?;
+    parse_config(&data).map_err(Error::InvalidConfig)
+}
 
+fn parse_config(data: &str) -> anyhow::Result<JoshConfig> {
+    let config: JoshConfig = toml::from_str(data).context("cannot load config as TOML")?;
+    let problems = config.validate();
+    if !problems.is_empty() {
+        return Err(anyhow::anyhow!("{}", problems.join("\n")));
+    }
     Ok(config)
 }
 
-/// Converts filters from old `:rev(sha:filter)` syntax to new
-/// `:rev(<=sha:filter)` syntax. Null SHAs (40 zeros) become `_`.
-/// Only touches SHAs inside `:rev(...)` blocks.
-fn convert_rev_syntax(input: &str) -> String {
-    let rev_block = regex::Regex::new(r":rev\([^)]*\)").unwrap();
-    let entry = regex::Regex::new(
-        r"(?x)
-        ([,(])                # delimiter before entry
-        (0{40}|[0-9a-f]{40})  # full SHA
-        :                     # colon separator
-    ",
-    )
-    .unwrap();
-
-    rev_block
-        .replace_all(input, |block: &regex::Captures| {
-            entry
-                .replace_all(&block[0], |caps: &regex::Captures| {
-                    let delim = &caps[1];
-                    let sha = &caps[2];
-                    if sha.chars().all(|c| c == '0') {
-                        format!("{delim}_:")
-                    } else {
-                        format!("{delim}<={sha}:")
-                    }
-                })
-                .into_owned()
-        })
-        .into_owned()
+/// Thoroughly checks the config file at `path` and the `rust-version` files of its subtrees.
+/// If `rustc_git` points to a rustc checkout, it also checks that the subtree paths exist
+/// upstream.
+///
+/// Returns all problems that were found.
+pub fn check_config(
+    path: &Path,
+    default_rust_version_path: &Path,
+    rustc_git: Option<&Path>,
+    verbose: bool,
+) -> Vec<String> {
+    let config = std::fs::read_to_string(path)
+        .with_context(|| format!("cannot load config file from {}", path.display()))
+        .and_then(|data| toml::from_str::<JoshConfig>(&data).context("cannot load config as TOML"));
+    let config = match config {
+        Ok(config) => config,
+        Err(error) => return vec![format!("{error:#}")],
+    };
+
+    let mut problems = config.validate();
+    for subtree in config.subtrees() {
+        problems.extend(subtree.check_subtree_filter_consistency());
+
+        let rust_version_path = subtree
+            .rust_version
+            .as_deref()
+            .unwrap_or(default_rust_version_path);
+        let upstream_sha = match std::fs::read_to_string(rust_version_path) {
+            Ok(content) if is_full_sha(content.trim()) => Some(content.trim().to_string()),
+            Ok(content) => {
+                problems.push(format!(
+                    "Subtree `{}`: {} should contain a 40-character commit SHA, found `{}`",
+                    subtree.name,
+                    rust_version_path.display(),
+                    content.trim()
+                ));
+                None
+            }
+            Err(error) => {
+                problems.push(format!(
+                    "Subtree `{}`: cannot read {}: {error}",
+                    subtree.name,
+                    rust_version_path.display()
+                ));
+                None
+            }
+        };
+
+        if let Some(rustc_git) = rustc_git
+            && let Some(upstream_path) = subtree.upstream_path()
+        {
+            let rev = upstream_sha.as_deref().unwrap_or("HEAD");
+            if run_command_at(
+                ["git", "cat-file", "-e", &format!("{rev}:{upstream_path}")],
+                rustc_git,
+                verbose,
+            )
+            .is_err()
+            {
+                problems.push(format!(
+                    "Subtree `{}`: path `{upstream_path}` does not exist at `{rev}` in the rustc checkout at {} (make sure that the checkout contains that commit)",
+                    subtree.name,
+                    rustc_git.display()
+                ));
+            }
+        }
+    }
+    problems
 }
 
-/// Wraps a filter with the backwards compatibility meta options for
-/// trivial merge preservation and CRLF normalization in gpgsig headers.
-///
-/// `:your/filter` becomes
-/// `:~(history="keep-trivial-merges",gpgsig="norm-lf")[:your/filter]`
-fn wrap_compat(filter: &str) -> String {
-    format!(":~(history=\"keep-trivial-merges\",gpgsig=\"norm-lf\")[{filter}]")
+fn check_path_and_filter(path: &Option<String>, filter: &Option<String>) -> Option<String> {
+    match (path, filter) {
+        (Some(_), Some(_)) => Some("Cannot specify both `path` and `filter`".to_string()),
+        (None, None) => Some("Must specify one of `path` and `filter`".to_string()),
+        _ => None,
+    }
 }
 
 #[cfg(test)]
@@ -124,92 +543,221 @@ mod tests {
     use super::*;
 
     #[test]
-    fn no_rev_block_unchanged() {
-        assert_eq!(convert_rev_syntax(":/some/path"), ":/some/path");
+    fn single_subtree_config() {
+        let config = parse_config(
+            r#"
+repo = "stdarch"
+path = "library/stdarch"
+"#,
+        )
+        .unwrap();
+        let subtrees = config.subtrees();
+        assert_eq!(subtrees.len(), 1);
+        assert_eq!(subtrees[0].name, "stdarch");
+        assert_eq!(subtrees[0].path.as_deref(), Some("library/stdarch"));
+        assert!(subtrees[0].rust_version.is_none());
     }
 
     #[test]
-    fn single_sha_gets_prefix() {
-        assert_eq!(
-            convert_rev_syntax(":rev(3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/some/path)"),
-            ":rev(<=3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/some/path)",
+    fn use_global_git_config() {
+        let config = parse_config(
+            r#"
+repo = "stdarch"
+path = "library/stdarch"
+"#,
+        )
+        .unwrap();
+        assert!(!config.use_global_git_config);
+        assert!(
+            !toml::to_string(&config)
+                .unwrap()
+                .contains("use-global-git-config")
         );
+
+        let config = parse_config(
+            r#"
+repo = "stdarch"
+path = "library/stdarch"
+use-global-git-config = true
+"#,
+        )
+        .unwrap();
+        assert!(config.use_global_git_config);
     }
 
     #[test]
-    fn null_sha_becomes_underscore() {
+    fn multiple_subtrees_config() {
+        let config = parse_config(
+            r#"
+repo = "tools"
+[[subtree]]
+name = "foo"
+path = "src/tools/foo"
+rust-version = "foo/rust-version"
+[[subtree]]
+name = "bar"
+filter = ":/src/tools/bar:prefix=bar"
+rust-version = "bar/rust-version"
+subtree-filter = ":/bar:prefix=bar"
+"#,
+        )
+        .unwrap();
+        let names =
+            |subtrees: Vec<SubtreeConfig>| subtrees.into_iter().map(|s| s.name).collect::<Vec<_>>();
+        assert_eq!(names(config.select_subtrees(&[]).unwrap()), ["foo", "bar"]);
         assert_eq!(
-            convert_rev_syntax(":rev(0000000000000000000000000000000000000000:/some/path)"),
-            ":rev(_:/some/path)",
+            names(config.select_subtrees(&["bar".to_string()]).unwrap()),
+            ["bar"]
         );
+        assert!(config.select_subtrees(&["baz".to_string()]).is_err());
     }
 
     #[test]
-    fn multiple_entries_in_rev_block() {
-        assert_eq!(
-            convert_rev_syntax(
-                ":rev(3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/p1,\
-                 e4c7a2d8f1b3e5a9d6c0f2b4a7e1d3c5f8a0b6e9:/p2,\
-                 0000000000000000000000000000000000000000:/p3)"
-            ),
-            ":rev(<=3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/p1,\
-             <=e4c7a2d8f1b3e5a9d6c0f2b4a7e1d3c5f8a0b6e9:/p2,\
-             _:/p3)",
+    fn subtrees_cannot_be_combined_with_top_level_path() {
+        assert!(
+            parse_config(
+                r#"
+repo = "tools"
+path = "src/tools/foo"
+[[subtree]]
+name = "bar"
+path = "src/tools/bar"
+rust-version = "bar/rust-version"
+"#,
+            )
+            .is_err()
         );
     }
 
     #[test]
-    fn already_converted_syntax_unchanged() {
-        assert_eq!(
-            convert_rev_syntax(":rev(<=3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/some/path)"),
-            ":rev(<=3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/some/path)",
+    fn subtree_requires_rust_version() {
+        assert!(
+            parse_config(
+                r#"
+repo = "tools"
+[[subtree]]
+name = "bar"
+path = "src/tools/bar"
+"#,
+            )
+            .is_err()
         );
     }
 
     #[test]
-    fn underscore_syntax_unchanged() {
-        assert_eq!(
-            convert_rev_syntax(":rev(_:/some/path)"),
-            ":rev(_:/some/path)",
+    fn duplicate_subtree_names() {
+        assert!(
+            parse_config(
+                r#"
+repo = "tools"
+[[subtree]]
+name = "foo"
+path = "src/tools/foo"
+rust-version = "foo/rust-version"
+[[subtree]]
+name = "foo"
+path = "src/tools/bar"
+rust-version = "bar/rust-version"
+"#,
+            )
+            .is_err()
         );
     }
 
     #[test]
-    fn sha_outside_rev_block_unchanged() {
-        assert_eq!(
-            convert_rev_syntax("3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/some/path"),
-            "3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/some/path",
-        );
+    fn invalid_filter_is_rejected() {
+        let error = parse_config(
+            r#"
+repo = "miri"
+filter = ":rev(75dd959:prefix=src/tools/miri):/src/tools/miri"
+"#,
+        )
+        .err()
+        .unwrap();
+        assert!(format!("{error:?}").contains("column 6"));
     }
 
     #[test]
-    fn wrap_compat_simple_filter() {
-        assert_eq!(
-            wrap_compat(":/some/path"),
-            ":~(history=\"keep-trivial-merges\",gpgsig=\"norm-lf\")[:/some/path]",
-        );
+    fn all_problems_are_reported() {
+        let config: JoshConfig = toml::from_str(
+            r#"
+repo = "tools"
+[[subtree]]
+name = "foo"
+path = "src/tools/foo"
+filter = ":/src/tools/foo"
+[[subtree]]
+name = "bar"
+filter = ":/src/tools/bar:prefix=bar"
+rust-version = "bar/rust-version"
+[[subtree.post-pull]]
+cmd = []
+commit-message = "reformat"
+"#,
+        )
+        .unwrap();
+        let problems = config.validate();
+        assert_eq!(problems.len(), 3, "{problems:?}");
+        assert!(problems[0].contains("Cannot specify both `path` and `filter`"));
+        assert!(problems[1].contains("must specify a `rust-version` path"));
+        assert!(problems[2].contains("empty `cmd`"));
     }
 
     #[test]
-    fn wrap_compat_rev_filter() {
-        assert_eq!(
-            wrap_compat(
-                ":rev(75dd959a3a40eb5b4574f8d2e23aa6efbeb33573:prefix=src/tools/miri):/src/tools/miri"
-            ),
-            ":~(history=\"keep-trivial-merges\",gpgsig=\"norm-lf\")\
-             [:rev(75dd959a3a40eb5b4574f8d2e23aa6efbeb33573:prefix=src/tools/miri):/src/tools/miri]",
+    fn subtree_filter_consistency() {
+        let subtree = |filter: &str, subtree_filter: &str| SubtreeConfig {
+            name: "foo".to_string(),
+            path: None,
+            filter: Some(filter.to_string()),
+            rust_version: None,
+            post_pull: vec![],
+            subtree_filter: Some(subtree_filter.to_string()),
+        };
+        assert!(
+            subtree(
+                ":/compiler/rustc_public:prefix=rustc_public",
+                ":/rustc_public:prefix=rustc_public"
+            )
+            .check_subtree_filter_consistency()
+            .is_none()
+        );
+        assert!(
+            subtree(
+                ":/compiler/rustc_public:prefix=rustc_public",
+                ":/rustc_public"
+            )
+            .check_subtree_filter_consistency()
+            .is_some()
+        );
+        assert!(
+            subtree(":/compiler/rustc_public", ":/foo:prefix=foo")
+                .check_subtree_filter_consistency()
+                .is_some()
         );
     }
 
     #[test]
-    fn multiple_rev_blocks() {
-        assert_eq!(
-            convert_rev_syntax(
-                ":rev(3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/p1)\
-                 :rev(e4c7a2d8f1b3e5a9d6c0f2b4a7e1d3c5f8a0b6e9:/p2)"
-            ),
-            ":rev(<=3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/p1)\
-             :rev(<=e4c7a2d8f1b3e5a9d6c0f2b4a7e1d3c5f8a0b6e9:/p2)",
-        );
+    fn upstream_repo_and_branch() {
+        let config = parse_config(
+            r#"
+repo = "stdarch"
+path = "library/stdarch"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.upstream_repo, "rust-lang/rust");
+        assert_eq!(config.upstream_ref(), "HEAD");
+
+        let config = parse_config(
+            r#"
+repo = "stdarch"
+path = "library/stdarch"
+upstream-repo = "my-org/rust-mirror"
+upstream-branch = "stable"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.upstream_repo_name(), "rust-mirror");
+        assert_eq!(config.upstream_ref(), "refs/heads/stable");
     }
 }
@@ -0,0 +1,638 @@
+//! Diagnostics of the environment in which josh-sync runs, performed by the `doctor` command.
+use crate::command::CommandRunner;
+use crate::config::{JoshConfig, load_config};
+use crate::josh::{JOSH_VERSION, JoshTool, installed_version, is_port_open, josh_cache_dir};
+use crate::utils::{is_full_sha, is_kept_git_config_key};
+use anyhow::Context;
+use std::path::Path;
+
+/// Oldest git version that supports everything used by josh-sync
+/// (`git merge-tree --write-tree` is used by `pull --dry-run`).
+const MIN_GIT_VERSION: (u32, u32) = (2, 38);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckStatus {
+    Ok,
+    /// Something that may cause problems, or that josh-sync will fix on its own.
+    Warning,
+    /// Something that will make a sync fail or go wrong.
+    Error,
+}
+
+/// Result of a single check.
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    /// What was found.
+    pub message: String,
+    /// How to fix the problem, if there is one.
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Ok,
+            message: message.into(),
+            fix: None,
+        }
+    }
+
+    fn warning(name: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Warning,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn error(name: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Error,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+/// Checks the config, the `rust-version` file(s) and the tools and directories needed for a
+/// sync. Git and `gh` are executed through `runner`, which has to run git outside of the
+/// hermetic git environment, so that the full git config can be checked.
+pub fn run_checks(
+    config_path: &Path,
+    default_rust_version_path: &Path,
+    rustc_git: Option<&Path>,
+    runner: &dyn CommandRunner,
+    verbose: bool,
+) -> Vec<Check> {
+    let mut checks = vec![];
+    let config = match load_config(config_path) {
+        Ok(config) => {
+            checks.push(Check::ok(
+                "config",
+                format!("{} is valid", config_path.display()),
+            ));
+            Some(config)
+        }
+        Err(error) => {
+            let fix = if config_path.is_file() {
+                "fix the problems, `rustc-josh-sync check-config` lists all of them"
+            } else {
+                "create the config using `rustc-josh-sync init`, or pass its path using `--config-path`"
+            };
+            let error = anyhow::Error::from(error);
+            checks.push(Check::error("config", format!("{error:#}"), fix));
+            None
+        }
+    };
+    if let Some(config) = &config {
+        checks.extend(check_rust_versions(config, default_rust_version_path));
+    }
+    checks.push(check_git_version(runner));
+    let hermetic = config
+        .as_ref()
+        .is_none_or(|config| !config.use_global_git_config);
+    checks.push(check_git_config(runner, hermetic));
+    let needs_josh_filter = config.as_ref().is_some_and(|config| {
+        config
+            .subtrees()
+            .iter()
+            .any(|subtree| subtree.subtree_filter.is_some())
+    });
+    checks.push(check_josh_tool(JoshTool::Proxy, true, verbose));
+    checks.push(check_josh_tool(
+        JoshTool::Filter,
+        needs_josh_filter,
+        verbose,
+    ));
+    if let Some(config) = &config {
+        checks.push(check_josh_port(config));
+    }
+    checks.push(check_cache_dirs(config.as_ref()));
+    checks.push(check_rustc_git(rustc_git, runner));
+    checks.push(check_gh(runner));
+    checks
+}
+
+fn check_rust_versions(config: &JoshConfig, default_rust_version_path: &Path) -> Vec<Check> {
+    config
+        .subtrees()
+        .iter()
+        .map(|subtree| {
+            let path = subtree
+                .rust_version
+                .as_deref()
+                .unwrap_or(default_rust_version_path);
+            check_rust_version(&subtree.name, path)
+        })
+        .collect()
+}
+
+fn check_rust_version(subtree: &str, path: &Path) -> Check {
+    const NAME: &str = "rust-version";
+    match std::fs::read_to_string(path) {
+        Ok(content) if is_full_sha(content.trim()) => Check::ok(
+            NAME,
+            format!(
+                "subtree `{subtree}` was last synced with rustc {}",
+                content.trim()
+            ),
+        ),
+        Ok(content) if content.trim().is_empty() => Check::ok(
+            NAME,
+            format!(
+                "subtree `{subtree}` was not pulled yet ({} is empty)",
+                path.display()
+            ),
+        ),
+        Ok(content) => Check::error(
+            NAME,
+            format!(
+                "{} of subtree `{subtree}` should contain a 40-character commit SHA, found `{}`",
+                path.display(),
+                content.trim()
+            ),
+            "write the full SHA of the last synchronized rustc commit into the file, or empty it if no pull was performed yet",
+        ),
+        Err(error) => Check::error(
+            NAME,
+            format!(
+                "cannot read {} of subtree `{subtree}`: {error}",
+                path.display()
+            ),
+            "create the file using `rustc-josh-sync init`, or fix its path in the config",
+        ),
+    }
+}
+
+fn check_git_version(runner: &dyn CommandRunner) -> Check {
+    const NAME: &str = "git version";
+    let (min_major, min_minor) = MIN_GIT_VERSION;
+    let output = match runner.run(&["git", "--version"]) {
+        Ok(output) => output,
+        Err(error) => {
+            return Check::error(
+                NAME,
+                format!("cannot run git: {error:#}"),
+                "install git and make sure that it is on the PATH",
+            );
+        }
+    };
+    match parse_git_version(&output) {
+        Some(version) if version >= MIN_GIT_VERSION => Check::ok(NAME, output),
+        Some(_) => Check::warning(
+            NAME,
+            format!(
+                "{output} is older than {min_major}.{min_minor}, which is needed for `pull --dry-run`"
+            ),
+            format!("upgrade git to at least {min_major}.{min_minor}"),
+        ),
+        None => Check::warning(
+            NAME,
+            format!("cannot determine the git version from `{output}`"),
+            format!("make sure that git is at least version {min_major}.{min_minor}"),
+        ),
+    }
+}
+
+/// Parses the major and minor version from the output of `git --version`,
+/// e.g. `git version 2.39.3 (Apple Git-145)`.
+fn parse_git_version(output: &str) -> Option<(u32, u32)> {
+    let version = output.strip_prefix("git version ")?;
+    let mut parts = version.split(['.', ' ']);
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Git config keys that change the outcome of a sync, with the value that causes problems
+/// and an explanation of the problem.
+const PROBLEMATIC_GIT_CONFIG: &[(&str, &str, &str)] = &[
+    (
+        "fetch.prunetags",
+        "true",
+        "fetched tags are pruned, which makes pulls report \"Nothing to pull\"",
+    ),
+    (
+        "commit.gpgsign",
+        "true",
+        "sync commits are signed, which can fail or prompt for a passphrase",
+    ),
+    (
+        "core.autocrlf",
+        "true",
+        "line endings are converted, which can make the working tree dirty after a pull",
+    ),
+];
+
+/// An entry of the git config, as listed by `git config --list --show-scope --show-origin`.
+struct GitConfigEntry {
+    /// `system`, `global`, `local`, `worktree` or `command`.
+    scope: String,
+    /// Where the entry is defined, e.g. `file:/home/user/.gitconfig`.
+    origin: String,
+    key: String,
+    value: Option<String>,
+}
+
+impl GitConfigEntry {
+    /// Returns true if the entry is ignored in the hermetic git environment, see
+    /// [`crate::utils::set_hermetic_git`].
+    fn is_ignored_by_hermetic_git(&self) -> bool {
+        matches!(self.scope.as_str(), "system" | "global") && !is_kept_git_config_key(&self.key)
+    }
+}
+
+/// Parses the output of `git config --list --null --show-scope --show-origin`.
+fn parse_git_config_entries(output: &str) -> Vec<GitConfigEntry> {
+    let mut fields = output.split('\0');
+    let mut entries = vec![];
+    while let (Some(scope), Some(origin), Some(entry)) =
+        (fields.next(), fields.next(), fields.next())
+    {
+        let (key, value) = match entry.split_once('\n') {
+            Some((key, value)) => (key, Some(value.to_string())),
+            None => (entry, None),
+        };
+        entries.push(GitConfigEntry {
+            scope: scope.to_string(),
+            origin: origin.to_string(),
+            key: key.to_string(),
+            value,
+        });
+    }
+    entries
+}
+
+/// Looks for problematic keys in the full git config, which has to be read by `runner` outside
+/// of the hermetic git environment. `hermetic` tells if the sync uses the hermetic environment,
+/// in which the keys from the global and system config do not apply.
+fn check_git_config(runner: &dyn CommandRunner, hermetic: bool) -> Check {
+    const NAME: &str = "git config";
+    let output = match runner.run(&[
+        "git",
+        "config",
+        "--list",
+        "--null",
+        "--includes",
+        "--show-scope",
+        "--show-origin",
+    ]) {
+        Ok(output) => output,
+        Err(error) => {
+            return Check::warning(
+                NAME,
+                format!("cannot read the git config: {error:#}"),
+                "make sure that the git config files can be parsed by `git config --list`",
+            );
+        }
+    };
+    let entries = parse_git_config_entries(&output);
+    let mut applied = vec![];
+    let mut ignored = vec![];
+    for (key, problematic_value, explanation) in PROBLEMATIC_GIT_CONFIG {
+        // Later entries override earlier ones
+        let mut matching = entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.key.eq_ignore_ascii_case(key));
+        let effective = if hermetic {
+            matching.find(|entry| !entry.is_ignored_by_hermetic_git())
+        } else {
+            matching.next()
+        };
+        let is_problematic = |entry: &&GitConfigEntry| {
+            is_git_config_value(entry.value.as_deref(), problematic_value)
+        };
+        if let Some(entry) = effective.filter(is_problematic) {
+            applied.push((entry, explanation));
+        } else if hermetic
+            && let Some(entry) = entries
+                .iter()
+                .rev()
+                .find(|entry| entry.key.eq_ignore_ascii_case(key))
+                .filter(is_problematic)
+        {
+            ignored.push(entry);
+        }
+    }
+
+    let ignored = ignored
+        .iter()
+        .map(|entry| {
+            format!(
+                "`{} = {}` from {} is ignored by josh-sync",
+                entry.key,
+                entry.value.as_deref().unwrap_or("true"),
+                entry.origin
+            )
+        })
+        .collect::<Vec<_>>();
+    if applied.is_empty() {
+        let message = if ignored.is_empty() {
+            "no git config keys that affect the sync are set".to_string()
+        } else {
+            ignored.join("; ")
+        };
+        return Check::ok(NAME, message);
+    }
+    let message = applied
+        .iter()
+        .map(|(entry, explanation)| {
+            format!(
+                "`{} = {}` from {} ({} config): {explanation}",
+                entry.key,
+                entry.value.as_deref().unwrap_or("true"),
+                entry.origin,
+                entry.scope
+            )
+        })
+        .chain(ignored)
+        .collect::<Vec<_>>()
+        .join("; ");
+    let unset = applied
+        .iter()
+        .map(|(entry, _)| match entry.origin.strip_prefix("file:") {
+            Some(file) => format!("`git config --file {file} --unset {}`", entry.key),
+            None => format!("remove `{}` from {}", entry.key, entry.origin),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut fix = format!("unset the keys: {unset}");
+    if !hermetic
+        && applied
+            .iter()
+            .any(|(entry, _)| matches!(entry.scope.as_str(), "system" | "global"))
+    {
+        fix.push_str(", or remove `use-global-git-config` from the config, so that josh-sync ignores the global git config");
+    }
+    Check::warning(NAME, message, fix)
+}
+
+/// Compares a git config value with the expected value, taking into account that booleans
+/// can be written in several ways, and that a key without a value means `true`.
+fn is_git_config_value(value: Option<&str>, expected: &str) -> bool {
+    let value = value.unwrap_or("true").to_ascii_lowercase();
+    match expected {
+        "true" => matches!(value.as_str(), "true" | "yes" | "on" | "1"),
+        _ => value == expected,
+    }
+}
+
+fn check_josh_tool(tool: JoshTool, needed: bool, verbose: bool) -> Check {
+    let name = tool.binary_name();
+    if let Some(path) = tool.find(verbose) {
+        return Check::ok(
+            name,
+            format!("{name} {JOSH_VERSION} is installed at {}", path.display()),
+        );
+    }
+    let install = "run `rustc-josh-sync install-josh` (which needs cargo), or let josh-sync install it on first use";
+    if !needed {
+        return Check::ok(
+            name,
+            format!("{name} {JOSH_VERSION} is not installed, but no subtree uses `subtree-filter`"),
+        );
+    }
+    match which::which(name) {
+        Ok(path) => {
+            let version = installed_version(&path, verbose)
+                .unwrap_or_else(|| "an unknown version".to_string());
+            Check::warning(
+                name,
+                format!(
+                    "{} is {version}, but josh-sync needs {JOSH_VERSION}",
+                    path.display()
+                ),
+                install,
+            )
+        }
+        Err(_) => Check::warning(
+            name,
+            format!("{name} {JOSH_VERSION} is not installed"),
+            install,
+        ),
+    }
+}
+
+fn check_josh_port(config: &JoshConfig) -> Check {
+    const NAME: &str = "josh port";
+    match config.josh_port {
+        Some(port) if is_port_open(port) => Check::error(
+            NAME,
+            format!("port {port} is already in use"),
+            format!(
+                "stop the process that listens on port {port} (maybe a stale josh-proxy instance), or change `josh-port` in the config"
+            ),
+        ),
+        Some(port) => Check::ok(NAME, format!("port {port} is free")),
+        None => Check::ok(NAME, "a free port is chosen for josh-proxy automatically"),
+    }
+}
+
+fn check_cache_dirs(config: Option<&JoshConfig>) -> Check {
+    const NAME: &str = "cache directories";
+    let mut dirs = vec![];
+    let mut problems = vec![];
+    match crate::josh::josh_install_dir() {
+        Ok(dir) => dirs.push(dir),
+        Err(error) => problems.push(format!("{error:#}")),
+    }
+    match directories::ProjectDirs::from("org", "rust-lang", "rustc-josh-sync") {
+        Some(project_dirs) => dirs.push(project_dirs.cache_dir().to_owned()),
+        None => problems.push("cannot determine cache directory for josh-sync".to_string()),
+    }
+    if let Some(config) = config {
+        match josh_cache_dir(config) {
+            Ok(dir) => dirs.push(dir),
+            Err(error) => problems.push(format!("{error:#}")),
+        }
+    }
+    problems.extend(
+        dirs.iter()
+            .filter_map(|dir| check_writable_dir(dir).err())
+            .map(|error| format!("{error:#}")),
+    );
+    if problems.is_empty() {
+        let dirs = dirs
+            .iter()
+            .map(|dir| dir.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        Check::ok(NAME, format!("{dirs} are writable"))
+    } else {
+        Check::error(
+            NAME,
+            problems.join("; "),
+            "make sure that the directories are writable, or delete them so that josh-sync recreates them",
+        )
+    }
+}
+
+/// Creates `dir` if needed, and checks that files can be written into it.
+fn check_writable_dir(dir: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    let probe = dir.join(format!(".doctor.{}", std::process::id()));
+    std::fs::write(&probe, "").with_context(|| format!("cannot write into {}", dir.display()))?;
+    std::fs::remove_file(&probe).with_context(|| format!("cannot delete {}", probe.display()))?;
+    Ok(())
+}
+
+fn check_rustc_git(rustc_git: Option<&Path>, runner: &dyn CommandRunner) -> Check {
+    const NAME: &str = "RUSTC_GIT";
+    let Some(rustc_git) = rustc_git else {
+        return Check::ok(
+            NAME,
+            "not set, `push` will clone rustc into the `rustc-checkout` directory",
+        );
+    };
+    if !rustc_git.is_dir() {
+        return Check::error(
+            NAME,
+            format!("{} is not a directory", rustc_git.display()),
+            "point RUSTC_GIT to a local clone of the rustc repository, or unset it",
+        );
+    }
+    match runner.run_at(&["git", "rev-parse", "--git-dir"], Some(rustc_git)) {
+        Ok(_) => Check::ok(NAME, format!("{} is a git repository", rustc_git.display())),
+        Err(_) => Check::error(
+            NAME,
+            format!("{} is not a git repository", rustc_git.display()),
+            "point RUSTC_GIT to a local clone of the rustc repository, or unset it",
+        ),
+    }
+}
+
+fn check_gh(runner: &dyn CommandRunner) -> Check {
+    const NAME: &str = "gh";
+    if let Some(var) = ["GITHUB_TOKEN", "GH_TOKEN"]
+        .into_iter()
+        .find(|var| std::env::var(var).is_ok_and(|token| !token.is_empty()))
+    {
+        return Check::ok(NAME, format!("the GitHub token is taken from {var}"));
+    }
+    if runner.run(&["gh", "--version"]).is_err() {
+        return Check::warning(
+            NAME,
+            "gh is not installed, and neither GITHUB_TOKEN nor GH_TOKEN is set",
+            "install gh (https://cli.github.com) and log in using `gh auth login`, or set GITHUB_TOKEN; it is needed by `pull-pr` and `push --open-pr`",
+        );
+    }
+    match runner.run(&["gh", "auth", "token"]) {
+        Ok(_) => Check::ok(NAME, "gh is installed and logged in"),
+        Err(_) => Check::warning(
+            NAME,
+            "gh is installed, but not logged in",
+            "log in using `gh auth login`; it is needed by `pull-pr` and `push --open-pr`",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::ScriptedRunner;
+
+    #[test]
+    fn git_version() {
+        assert_eq!(
+            parse_git_version("git version 2.39.3 (Apple Git-145)"),
+            Some((2, 39))
+        );
+        assert_eq!(
+            parse_git_version("git version 2.45.1.windows.1"),
+            Some((2, 45))
+        );
+        assert_eq!(parse_git_version("hub version 2.14.2"), None);
+
+        let runner = ScriptedRunner::new().expect("git --version", "git version 2.34.1");
+        let check = check_git_version(&runner);
+        assert_eq!(check.status, CheckStatus::Warning);
+        assert!(check.fix.unwrap().contains("2.38"));
+        let runner = ScriptedRunner::new().expect("git --version", "git version 2.43.0");
+        assert_eq!(check_git_version(&runner).status, CheckStatus::Ok);
+    }
+
+    #[test]
+    fn problematic_git_config() {
+        const COMMAND: &str = "git config --list --null --includes --show-scope --show-origin";
+        let output = "global\0file:/home/a/.gitconfig\0fetch.prunetags\ntrue\0\
+            global\0file:/home/a/.gitconfig\0core.autocrlf\ntrue\0\
+            local\0file:.git/config\0core.autocrlf\nfalse\0\
+            local\0file:.git/config\0commit.gpgsign\0";
+
+        // Keys from the global config are ignored in the hermetic git environment
+        let runner = ScriptedRunner::new().expect(COMMAND, output);
+        let check = check_git_config(&runner, true);
+        assert_eq!(check.status, CheckStatus::Warning);
+        assert!(check.message.contains(
+            "`fetch.prunetags = true` from file:/home/a/.gitconfig is ignored by josh-sync"
+        ));
+        // Overridden by a later entry
+        assert!(!check.message.contains("core.autocrlf"));
+        // A key without a value means `true`
+        assert!(
+            check
+                .message
+                .contains("`commit.gpgsign = true` from file:.git/config (local config)")
+        );
+        assert_eq!(
+            check.fix.as_deref(),
+            Some("unset the keys: `git config --file .git/config --unset commit.gpgsign`")
+        );
+
+        // ...but not with `use-global-git-config`
+        let runner = ScriptedRunner::new().expect(COMMAND, output);
+        let check = check_git_config(&runner, false);
+        assert!(
+            check
+                .message
+                .contains("`fetch.prunetags = true` from file:/home/a/.gitconfig (global config)")
+        );
+        assert!(
+            check
+                .fix
+                .unwrap()
+                .contains("remove `use-global-git-config`")
+        );
+
+        let runner = ScriptedRunner::new().expect(
+            COMMAND,
+            "global\0file:/home/a/.gitconfig\0user.name\nJane Doe\0\
+            local\0file:.git/config\0fetch.prunetags\nfalse\0",
+        );
+        assert_eq!(check_git_config(&runner, true).status, CheckStatus::Ok);
+    }
+
+    #[test]
+    fn rust_version_format() {
+        let path = std::env::temp_dir().join(format!(
+            "josh-sync-doctor-rust-version-{}",
+            std::process::id()
+        ));
+        std::fs::write(&path, "").unwrap();
+        assert_eq!(check_rust_version("a", &path).status, CheckStatus::Ok);
+        std::fs::write(&path, format!("{}\n", "a".repeat(40))).unwrap();
+        assert_eq!(check_rust_version("a", &path).status, CheckStatus::Ok);
+        std::fs::write(&path, "abc123\n").unwrap();
+        assert_eq!(check_rust_version("a", &path).status, CheckStatus::Error);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(check_rust_version("a", &path).status, CheckStatus::Error);
+    }
+
+    #[test]
+    fn rustc_git() {
+        let runner = ScriptedRunner::new().expect_failure("git rev-parse --git-dir");
+        let check = check_rustc_git(Some(&std::env::temp_dir()), &runner);
+        assert_eq!(check.status, CheckStatus::Error);
+        assert!(runner.is_done());
+
+        let check = check_rustc_git(Some(Path::new("/nonexistent/rustc")), &runner);
+        assert_eq!(check.status, CheckStatus::Error);
+        assert_eq!(check_rustc_git(None, &runner).status, CheckStatus::Ok);
+    }
+}
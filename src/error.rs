@@ -0,0 +1,168 @@
+//! Errors of the pull and push operations.
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A failure of a pull or push.
+///
+/// Errors of git commands and other unexpected failures are reported as [`Error::Other`],
+/// possibly wrapping one of the other variants. Use [`Error::kind`] to classify an error.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// No changes are available to be pulled.
+    #[error("nothing to pull")]
+    NothingToPull,
+    /// The config file cannot be loaded or is invalid.
+    #[error("invalid config")]
+    InvalidConfig(#[source] anyhow::Error),
+    /// The `rust-version` file is empty, so the operation cannot determine the last pull.
+    #[error("no pull has been performed yet, the rust-version file is empty")]
+    NoPreviousPull,
+    /// The upstream commit passed by the user is not a full commit SHA.
+    #[error("`{0}` is not a full 40-character commit SHA of the upstream repository")]
+    InvalidUpstreamCommit(String),
+    /// There are uncommitted changes in the working directory.
+    #[error("working directory must be clean")]
+    DirtyWorktree,
+    #[error(
+        "a pull is already in progress, run `rustc-josh-sync pull --continue` or `rustc-josh-sync pull --abort` first"
+    )]
+    PullInProgress,
+    #[error("no pull is in progress")]
+    NoPullInProgress,
+    #[error(
+        "a push is already in progress, run `rustc-josh-sync push --resume` or `rustc-josh-sync push --cleanup` first"
+    )]
+    PushInProgress,
+    #[error("no push is in progress")]
+    NoPushInProgress,
+    /// The merge of the upstream changes has failed, probably because of conflicts.
+    /// The pull can be finished with [`crate::sync::continue_pull`] or rolled back with
+    /// [`crate::sync::abort_pull`].
+    #[error("the merge of the upstream changes has failed, probably because of conflicts")]
+    MergeConflict(#[source] anyhow::Error),
+    /// An interrupted pull cannot be finished, because some files still have conflicts.
+    #[error(
+        "there are still unresolved conflicts, `git add` the following files after resolving them:\n{0}"
+    )]
+    UnresolvedConflicts(String),
+    /// `josh-proxy` or `josh-filter` cannot be installed or started.
+    #[error("Josh is not available")]
+    JoshUnavailable(#[source] anyhow::Error),
+    /// Josh has introduced a new root commit during a pull.
+    #[error("Josh created a new root commit. This is probably not the history you want.")]
+    NewRootCommit,
+    /// The pushed branch does not map back to the local history.
+    #[error(
+        "Josh created a non-roundtrip push! Do NOT merge this into rustc!\nExpected {expected}, got {actual}."
+    )]
+    NonRoundtripPush { expected: String, actual: String },
+    /// A new branch should be pushed, but it already exists in the fork.
+    #[error(
+        "The branch `{branch}` seems to already exist in {fork}. Please delete it and try again, or pass `--update` to update it."
+    )]
+    BranchExists { branch: String, fork: String },
+    /// An existing branch should be updated, but it does not exist in the fork.
+    #[error(
+        "The branch `{branch}` does not exist in {fork}, so it cannot be updated. Push without `--update`."
+    )]
+    BranchMissing { branch: String, fork: String },
+    /// An existing branch should be updated, but it is not based on the last pulled upstream
+    /// commit.
+    #[error(
+        "The branch `{branch}` is based on a different upstream commit than {base} from the rust-version file. \
+        It was probably created before the last pull, so it cannot be updated."
+    )]
+    BranchBaseMismatch { branch: String, base: String },
+    /// A command, usually git, has failed.
+    #[error(
+        "Command `{command}` failed with exit code {code:?}. STDOUT:\n{stdout}\nSTDERR:\n{stderr}"
+    )]
+    CommandFailed {
+        command: String,
+        code: Option<i32>,
+        stdout: String,
+        stderr: String,
+    },
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        Self::Other(error)
+    }
+}
+
+/// Classification of [`Error`]s, without their details.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    NothingToPull,
+    /// The config, the `rust-version` file or a command line argument is invalid.
+    InvalidConfig,
+    DirtyWorktree,
+    /// Another pull or push is in progress, or no pull or push is in progress although it
+    /// should be.
+    SyncState,
+    MergeConflict,
+    JoshUnavailable,
+    /// Josh has created a history that should not be merged.
+    InvalidHistory,
+    /// The branch in the fork is not in the expected state.
+    ForkBranch,
+    CommandFailed,
+    Other,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::NothingToPull => ErrorKind::NothingToPull,
+            Error::InvalidConfig(_) | Error::NoPreviousPull | Error::InvalidUpstreamCommit(_) => {
+                ErrorKind::InvalidConfig
+            }
+            Error::DirtyWorktree => ErrorKind::DirtyWorktree,
+            Error::PullInProgress
+            | Error::NoPullInProgress
+            | Error::PushInProgress
+            | Error::NoPushInProgress => ErrorKind::SyncState,
+            Error::MergeConflict(_) | Error::UnresolvedConflicts(_) => ErrorKind::MergeConflict,
+            Error::JoshUnavailable(_) => ErrorKind::JoshUnavailable,
+            Error::NewRootCommit | Error::NonRoundtripPush { .. } => ErrorKind::InvalidHistory,
+            Error::BranchExists { .. }
+            | Error::BranchMissing { .. }
+            | Error::BranchBaseMismatch { .. } => ErrorKind::ForkBranch,
+            Error::CommandFailed { .. } => ErrorKind::CommandFailed,
+            Error::Other(error) => error_kind(error),
+        }
+    }
+}
+
+/// Classifies an error that might wrap an [`Error`], possibly with some added context.
+pub fn error_kind(error: &anyhow::Error) -> ErrorKind {
+    error
+        .downcast_ref::<Error>()
+        .map(Error::kind)
+        .unwrap_or(ErrorKind::Other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn classify_wrapped_errors() {
+        let error: Error = Err::<(), _>(Error::DirtyWorktree)
+            .context("cannot pull")
+            .unwrap_err()
+            .into();
+        assert_eq!(error.kind(), ErrorKind::DirtyWorktree);
+        assert_eq!(
+            Error::from(anyhow::anyhow!("unexpected")).kind(),
+            ErrorKind::Other
+        );
+        let error = anyhow::Error::new(Error::Other(anyhow::Error::new(Error::NewRootCommit)));
+        assert_eq!(error_kind(&error), ErrorKind::InvalidHistory);
+    }
+}
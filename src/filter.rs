@@ -0,0 +1,704 @@
+//! Parser and AST for Josh filter specifications.
+//!
+//! Only the subset of the Josh filter language that is relevant for subtree synchronization
+//! is modelled precisely (subdirectories, files, prefixes, compositions, `:rev` and the
+//! `:~(...)` meta options). Other parenthesized filters are kept verbatim, so that they
+//! survive a parse/print round-trip.
+use std::fmt;
+
+/// A parsed Josh filter, i.e. a chain of filter operations that are applied in order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    pub ops: Vec<Op>,
+}
+
+/// A single filter operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// `:/`
+    Nop,
+    /// `:/path`
+    Subdir(String),
+    /// `::path`
+    File(String),
+    /// `:[a,b]`, or a named composition such as `:exclude[a,b]`.
+    Compose {
+        name: Option<String>,
+        filters: Vec<Filter>,
+    },
+    /// `:~(key="value",...)[a,b]`
+    Meta {
+        options: Vec<(String, String)>,
+        filters: Vec<Filter>,
+    },
+    /// `:rev(<=sha:filter,...)`
+    Rev(Vec<RevEntry>),
+    /// `:name(...)`, with the contents of the parentheses kept verbatim.
+    Call { name: String, args: String },
+    /// `:name=arg1;arg2`, e.g. `:prefix=path`.
+    Param { name: String, args: Vec<String> },
+    /// `:name`, e.g. `:SQUASH`.
+    Flag(String),
+}
+
+/// A single `<selector><filter>` entry of a `:rev(...)` filter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RevEntry {
+    pub selector: RevSelector,
+    pub filter: Filter,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RevSelector {
+    /// Old syntax, a bare SHA.
+    Legacy(String),
+    /// `<=sha`
+    AncestorsOf(String),
+    /// `==sha`
+    Exactly(String),
+    /// `_`
+    Default,
+}
+
+/// Error produced when a filter cannot be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterParseError {
+    pub filter: String,
+    /// 1-based column of the offending character.
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for FilterParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "invalid Josh filter at column {}: {}",
+            self.column, self.message
+        )?;
+        writeln!(f, "  {}", self.filter)?;
+        write!(f, "  {}^", " ".repeat(self.column - 1))
+    }
+}
+
+impl std::error::Error for FilterParseError {}
+
+impl Filter {
+    pub fn parse(input: &str) -> Result<Self, FilterParseError> {
+        let mut parser = Parser {
+            input,
+            chars: input.chars().collect(),
+            pos: 0,
+        };
+        let filter = parser.chain()?;
+        if filter.ops.is_empty() {
+            return Err(parser.error("expected a filter starting with `:`"));
+        }
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error(format!("unexpected `{}`", parser.chars[parser.pos])));
+        }
+        Ok(filter)
+    }
+
+    /// Converts `:rev` entries from the old `sha:filter` syntax to the new
+    /// `<=sha:filter` syntax. Null SHAs (40 zeros) become `_`.
+    pub fn convert_rev_syntax(&mut self) {
+        for op in &mut self.ops {
+            match op {
+                Op::Rev(entries) => {
+                    for entry in entries {
+                        if let RevSelector::Legacy(sha) = &entry.selector {
+                            entry.selector = if sha.chars().all(|c| c == '0') {
+                                RevSelector::Default
+                            } else {
+                                RevSelector::AncestorsOf(sha.clone())
+                            };
+                        }
+                        entry.filter.convert_rev_syntax();
+                    }
+                }
+                Op::Compose { filters, .. } | Op::Meta { filters, .. } => {
+                    for filter in filters {
+                        filter.convert_rev_syntax();
+                    }
+                }
+                Op::Nop
+                | Op::Subdir(_)
+                | Op::File(_)
+                | Op::Call { .. }
+                | Op::Param { .. }
+                | Op::Flag(_) => {}
+            }
+        }
+    }
+
+    /// Wraps the filter with the backwards compatibility meta options for
+    /// trivial merge preservation and CRLF normalization in gpgsig headers.
+    ///
+    /// `:your/filter` becomes
+    /// `:~(history="keep-trivial-merges",gpgsig="norm-lf")[:your/filter]`
+    pub fn wrap_compat(self) -> Self {
+        Self {
+            ops: vec![Op::Meta {
+                options: vec![
+                    ("history".to_string(), "keep-trivial-merges".to_string()),
+                    ("gpgsig".to_string(), "norm-lf".to_string()),
+                ],
+                filters: vec![self],
+            }],
+        }
+    }
+
+    /// Returns the prefix added by the last operation of the filter, if it is `:prefix=...`.
+    pub fn trailing_prefix(&self) -> Option<&str> {
+        match self.ops.last() {
+            Some(Op::Param { name, args }) if name == "prefix" && args.len() == 1 => Some(&args[0]),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for op in &self.ops {
+            write!(f, "{op}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Nop => write!(f, ":/"),
+            Op::Subdir(path) => write!(f, ":/{path}"),
+            Op::File(path) => write!(f, "::{path}"),
+            Op::Compose { name, filters } => {
+                write!(f, ":{}[", name.as_deref().unwrap_or(""))?;
+                write_list(f, filters)?;
+                write!(f, "]")
+            }
+            Op::Meta { options, filters } => {
+                write!(f, ":~(")?;
+                for (index, (key, value)) in options.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{key}=\"{value}\"")?;
+                }
+                write!(f, ")[")?;
+                write_list(f, filters)?;
+                write!(f, "]")
+            }
+            Op::Rev(entries) => {
+                write!(f, ":rev(")?;
+                for (index, entry) in entries.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    match &entry.selector {
+                        RevSelector::Legacy(sha) => write!(f, "{sha}")?,
+                        RevSelector::AncestorsOf(sha) => write!(f, "<={sha}")?,
+                        RevSelector::Exactly(sha) => write!(f, "=={sha}")?,
+                        RevSelector::Default => write!(f, "_")?,
+                    }
+                    write!(f, "{}", entry.filter)?;
+                }
+                write!(f, ")")
+            }
+            Op::Call { name, args } => write!(f, ":{name}({args})"),
+            Op::Param { name, args } => write!(f, ":{name}={}", args.join(";")),
+            Op::Flag(name) => write!(f, ":{name}"),
+        }
+    }
+}
+
+fn write_list(f: &mut fmt::Formatter<'_>, filters: &[Filter]) -> fmt::Result {
+    for (index, filter) in filters.iter().enumerate() {
+        if index > 0 {
+            write!(f, ",")?;
+        }
+        write!(f, "{filter}")?;
+    }
+    Ok(())
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: impl Into<String>) -> FilterParseError {
+        self.error_at(self.pos, message)
+    }
+
+    fn error_at(&self, pos: usize, message: impl Into<String>) -> FilterParseError {
+        FilterParseError {
+            filter: self.input.to_string(),
+            column: pos + 1,
+            message: message.into(),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), FilterParseError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            match self.peek() {
+                Some(found) => Err(self.error(format!("expected `{c}`, found `{found}`"))),
+                None => Err(self.error(format!("expected `{c}`, found end of filter"))),
+            }
+        }
+    }
+
+    /// Skips whitespace and returns `true` if it contained a newline.
+    fn skip_whitespace(&mut self) -> bool {
+        let mut newline = false;
+        while let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+            newline |= c == '\n';
+            self.pos += 1;
+        }
+        newline
+    }
+
+    /// Parses a (possibly empty) chain of operations.
+    fn chain(&mut self) -> Result<Filter, FilterParseError> {
+        let mut ops = vec![];
+        while self.eat(':') {
+            ops.push(self.op()?);
+        }
+        Ok(Filter { ops })
+    }
+
+    /// Parses a single operation, after its leading `:`.
+    fn op(&mut self) -> Result<Op, FilterParseError> {
+        match self.peek() {
+            Some('/') => {
+                self.pos += 1;
+                let path = self.argument();
+                Ok(if path.is_empty() {
+                    Op::Nop
+                } else {
+                    Op::Subdir(path)
+                })
+            }
+            Some(':') => {
+                self.pos += 1;
+                let path = self.argument();
+                if path.is_empty() {
+                    return Err(self.error("expected a path after `::`"));
+                }
+                Ok(Op::File(path))
+            }
+            Some('[') => Ok(Op::Compose {
+                name: None,
+                filters: self.list()?,
+            }),
+            Some('~') => {
+                self.pos += 1;
+                let options = self.meta_options()?;
+                Ok(Op::Meta {
+                    options,
+                    filters: self.list()?,
+                })
+            }
+            Some(c) if is_ident_char(c) => {
+                let name = self.ident();
+                match self.peek() {
+                    Some('[') => Ok(Op::Compose {
+                        name: Some(name),
+                        filters: self.list()?,
+                    }),
+                    Some('(') if name == "rev" => Ok(Op::Rev(self.rev_entries()?)),
+                    Some('(') => Ok(Op::Call {
+                        name,
+                        args: self.balanced_parens()?,
+                    }),
+                    Some('=') => {
+                        self.pos += 1;
+                        let mut args = vec![self.param_argument()?];
+                        while self.eat(';') {
+                            args.push(self.param_argument()?);
+                        }
+                        Ok(Op::Param { name, args })
+                    }
+                    _ => Ok(Op::Flag(name)),
+                }
+            }
+            Some(c) => Err(self.error(format!("unexpected `{c}` after `:`"))),
+            None => Err(self.error("expected a filter after `:`")),
+        }
+    }
+
+    fn ident(&mut self) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(is_ident_char) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    /// Parses an unquoted argument, such as a path.
+    fn argument(&mut self) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(is_argument_char) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    /// Parses a (possibly quoted) argument of a `:name=...` filter.
+    /// Quotes are kept, so that the argument can be printed back verbatim.
+    fn param_argument(&mut self) -> Result<String, FilterParseError> {
+        if self.peek() == Some('"') {
+            return Ok(format!("\"{}\"", self.string()?));
+        }
+        let argument = self.argument();
+        if argument.is_empty() {
+            return Err(self.error("expected an argument"));
+        }
+        Ok(argument)
+    }
+
+    /// Parses a double-quoted string and returns its contents.
+    fn string(&mut self) -> Result<String, FilterParseError> {
+        let start = self.pos;
+        self.expect('"')?;
+        let content_start = self.pos;
+        while let Some(c) = self.peek() {
+            if c == '"' {
+                let content = self.chars[content_start..self.pos].iter().collect();
+                self.pos += 1;
+                return Ok(content);
+            }
+            self.pos += 1;
+        }
+        Err(self.error_at(start, "unterminated string"))
+    }
+
+    /// Parses `[filter,filter,...]`. Filters can be separated by commas or newlines.
+    fn list(&mut self) -> Result<Vec<Filter>, FilterParseError> {
+        self.expect('[')?;
+        let mut filters = vec![];
+        loop {
+            self.skip_whitespace();
+            if self.eat(']') {
+                return Ok(filters);
+            }
+            let start = self.pos;
+            let filter = self.chain()?;
+            if filter.ops.is_empty() {
+                return Err(self.error_at(start, "expected a filter starting with `:`"));
+            }
+            filters.push(filter);
+            let newline = self.skip_whitespace();
+            if !self.eat(',') && self.peek() != Some(']') && !newline {
+                return match self.peek() {
+                    Some(c) => Err(self.error(format!("expected `,` or `]`, found `{c}`"))),
+                    None => Err(self.error("expected `]`, found end of filter")),
+                };
+            }
+        }
+    }
+
+    /// Parses `(key="value",...)`.
+    fn meta_options(&mut self) -> Result<Vec<(String, String)>, FilterParseError> {
+        self.expect('(')?;
+        let mut options = vec![];
+        loop {
+            self.skip_whitespace();
+            if self.eat(')') {
+                return Ok(options);
+            }
+            let key = self.ident();
+            if key.is_empty() {
+                return Err(self.error("expected an option name"));
+            }
+            self.expect('=')?;
+            let value = self.string()?;
+            options.push((key, value));
+            self.skip_whitespace();
+            if !self.eat(',') && self.peek() != Some(')') {
+                return Err(self.error("expected `,` or `)`"));
+            }
+        }
+    }
+
+    /// Parses `(selector filter,...)` of a `:rev` filter.
+    fn rev_entries(&mut self) -> Result<Vec<RevEntry>, FilterParseError> {
+        self.expect('(')?;
+        let mut entries = vec![];
+        loop {
+            self.skip_whitespace();
+            if self.eat(')') {
+                return Ok(entries);
+            }
+            let selector = self.rev_selector()?;
+            let start = self.pos;
+            let filter = self.chain()?;
+            if filter.ops.is_empty() {
+                return Err(self.error_at(start, "expected a filter starting with `:`"));
+            }
+            entries.push(RevEntry { selector, filter });
+            self.skip_whitespace();
+            if !self.eat(',') && self.peek() != Some(')') {
+                return match self.peek() {
+                    Some(c) => Err(self.error(format!("expected `,` or `)`, found `{c}`"))),
+                    None => Err(self.error("expected `)`, found end of filter")),
+                };
+            }
+        }
+    }
+
+    fn rev_selector(&mut self) -> Result<RevSelector, FilterParseError> {
+        if self.eat('_') {
+            return Ok(RevSelector::Default);
+        }
+        if self.peek() == Some('<') {
+            self.pos += 1;
+            self.expect('=')?;
+            return Ok(RevSelector::AncestorsOf(self.sha()?));
+        }
+        if self.peek() == Some('=') {
+            self.pos += 1;
+            self.expect('=')?;
+            return Ok(RevSelector::Exactly(self.sha()?));
+        }
+        Ok(RevSelector::Legacy(self.sha()?))
+    }
+
+    fn sha(&mut self) -> Result<String, FilterParseError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric()) {
+            self.pos += 1;
+        }
+        let sha: String = self.chars[start..self.pos].iter().collect();
+        if sha.len() != 40 || !sha.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')) {
+            return Err(self.error_at(
+                start,
+                format!("expected a full 40-character commit SHA, found `{sha}`"),
+            ));
+        }
+        Ok(sha)
+    }
+
+    /// Returns the contents of a balanced `(...)` group.
+    fn balanced_parens(&mut self) -> Result<String, FilterParseError> {
+        let start = self.pos;
+        self.expect('(')?;
+        let mut depth = 1;
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(self.chars[start + 1..self.pos - 1].iter().collect());
+                    }
+                }
+                _ => {}
+            }
+        }
+        Err(self.error_at(start, "unclosed `(`"))
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+fn is_argument_char(c: char) -> bool {
+    !c.is_whitespace() && !matches!(c, ':' | ',' | ';' | '[' | ']' | '(' | ')' | '"')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert_rev_syntax(input: &str) -> String {
+        let mut filter = Filter::parse(input).unwrap();
+        filter.convert_rev_syntax();
+        filter.to_string()
+    }
+
+    fn wrap_compat(input: &str) -> String {
+        Filter::parse(input).unwrap().wrap_compat().to_string()
+    }
+
+    fn roundtrip(input: &str) {
+        assert_eq!(Filter::parse(input).unwrap().to_string(), input);
+    }
+
+    fn error_column(input: &str) -> usize {
+        Filter::parse(input).unwrap_err().column
+    }
+
+    #[test]
+    fn no_rev_block_unchanged() {
+        assert_eq!(convert_rev_syntax(":/some/path"), ":/some/path");
+    }
+
+    #[test]
+    fn single_sha_gets_prefix() {
+        assert_eq!(
+            convert_rev_syntax(":rev(3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/some/path)"),
+            ":rev(<=3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/some/path)",
+        );
+    }
+
+    #[test]
+    fn null_sha_becomes_underscore() {
+        assert_eq!(
+            convert_rev_syntax(":rev(0000000000000000000000000000000000000000:/some/path)"),
+            ":rev(_:/some/path)",
+        );
+    }
+
+    #[test]
+    fn multiple_entries_in_rev_block() {
+        assert_eq!(
+            convert_rev_syntax(
+                ":rev(3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/p1,\
+                 e4c7a2d8f1b3e5a9d6c0f2b4a7e1d3c5f8a0b6e9:/p2,\
+                 0000000000000000000000000000000000000000:/p3)"
+            ),
+            ":rev(<=3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/p1,\
+             <=e4c7a2d8f1b3e5a9d6c0f2b4a7e1d3c5f8a0b6e9:/p2,\
+             _:/p3)",
+        );
+    }
+
+    #[test]
+    fn already_converted_syntax_unchanged() {
+        assert_eq!(
+            convert_rev_syntax(":rev(<=3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/some/path)"),
+            ":rev(<=3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/some/path)",
+        );
+    }
+
+    #[test]
+    fn underscore_syntax_unchanged() {
+        assert_eq!(
+            convert_rev_syntax(":rev(_:/some/path)"),
+            ":rev(_:/some/path)",
+        );
+    }
+
+    #[test]
+    fn sha_outside_rev_block_is_rejected() {
+        assert_eq!(
+            error_column("3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/some/path"),
+            1
+        );
+    }
+
+    #[test]
+    fn wrap_compat_simple_filter() {
+        assert_eq!(
+            wrap_compat(":/some/path"),
+            ":~(history=\"keep-trivial-merges\",gpgsig=\"norm-lf\")[:/some/path]",
+        );
+    }
+
+    #[test]
+    fn wrap_compat_rev_filter() {
+        assert_eq!(
+            wrap_compat(
+                ":rev(75dd959a3a40eb5b4574f8d2e23aa6efbeb33573:prefix=src/tools/miri):/src/tools/miri"
+            ),
+            ":~(history=\"keep-trivial-merges\",gpgsig=\"norm-lf\")\
+             [:rev(75dd959a3a40eb5b4574f8d2e23aa6efbeb33573:prefix=src/tools/miri):/src/tools/miri]",
+        );
+    }
+
+    #[test]
+    fn multiple_rev_blocks() {
+        assert_eq!(
+            convert_rev_syntax(
+                ":rev(3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/p1)\
+                 :rev(e4c7a2d8f1b3e5a9d6c0f2b4a7e1d3c5f8a0b6e9:/p2)"
+            ),
+            ":rev(<=3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/p1)\
+             :rev(<=e4c7a2d8f1b3e5a9d6c0f2b4a7e1d3c5f8a0b6e9:/p2)",
+        );
+    }
+
+    #[test]
+    fn nested_rev_block_converted() {
+        assert_eq!(
+            convert_rev_syntax(":[:rev(3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/p1),:/p2]"),
+            ":[:rev(<=3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/p1),:/p2]",
+        );
+    }
+
+    #[test]
+    fn roundtrip_printing() {
+        roundtrip(":/");
+        roundtrip(":/compiler/rustc_public:prefix=rustc_public");
+        roundtrip(":[:/a,::b/c.rs,:exclude[::d/]]");
+        roundtrip(":~(history=\"keep-trivial-merges\",gpgsig=\"norm-lf\")[:/src/tools/miri]");
+        roundtrip(":rev(==3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/p1,_:/p2):SQUASH");
+        roundtrip(":join(3a1f5e2b9c8d4e7f6a0b1c2d3e4f5a6b7c8d9e0f:/p)");
+        roundtrip(":author=\"Jane Doe\";\"jane@example.com\"");
+    }
+
+    #[test]
+    fn newline_separated_compose() {
+        assert_eq!(
+            Filter::parse(":[\n  :/a\n  :/b\n]").unwrap().to_string(),
+            ":[:/a,:/b]"
+        );
+    }
+
+    #[test]
+    fn short_sha_is_rejected() {
+        let error = Filter::parse(":rev(3a1f5e2:/some/path)").unwrap_err();
+        assert_eq!(error.column, 6);
+        assert!(error.message.contains("3a1f5e2"));
+    }
+
+    #[test]
+    fn malformed_filters_report_column() {
+        assert_eq!(error_column(""), 1);
+        assert_eq!(error_column("/some/path"), 1);
+        assert_eq!(error_column(":/a:"), 5);
+        assert_eq!(error_column(":[:/a,:/b"), 10);
+        assert_eq!(error_column(":[:/a;:/b]"), 6);
+        assert_eq!(error_column(":rev(_/a)"), 7);
+        assert_eq!(error_column(":~(history=keep)[:/a]"), 12);
+        assert_eq!(error_column(":prefix="), 9);
+        assert_eq!(error_column(":/a)"), 4);
+    }
+
+    #[test]
+    fn error_display_points_at_column() {
+        let error = Filter::parse(":/a)").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid Josh filter at column 4: unexpected `)`\n  :/a)\n     ^"
+        );
+    }
+
+    #[test]
+    fn trailing_prefix() {
+        let filter = Filter::parse(":/compiler/rustc_public:prefix=rustc_public").unwrap();
+        assert_eq!(filter.trailing_prefix(), Some("rustc_public"));
+        assert_eq!(Filter::parse(":/a").unwrap().trailing_prefix(), None);
+    }
+}
@@ -0,0 +1,191 @@
+use std::path::PathBuf;
+
+/// Service that hosts the upstream repository, forks of it and the subtree repository.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Forge {
+    /// https://github.com
+    #[default]
+    #[serde(rename = "github")]
+    GitHub,
+    /// A GitHub Enterprise server, e.g. `https://github.example.com`.
+    #[serde(rename = "github-enterprise")]
+    GitHubEnterprise { url: String },
+    /// A GitLab instance, `https://gitlab.com` by default.
+    #[serde(rename = "gitlab")]
+    GitLab {
+        #[serde(default = "default_gitlab_url")]
+        url: String,
+    },
+    /// A plain git server without a web interface.
+    /// Repositories are expected to be available at `<url>/<org>/<repo>`.
+    Git { url: String },
+    /// A directory on the local filesystem.
+    /// Repositories are expected to be available at `<path>/<org>/<repo>`.
+    File { path: PathBuf },
+}
+
+fn default_gitlab_url() -> String {
+    String::from("https://gitlab.com")
+}
+
+impl Forge {
+    pub fn is_github(&self) -> bool {
+        matches!(self, Forge::GitHub)
+    }
+
+    /// Base URL of the forge, which is also passed to `josh-proxy` as its remote.
+    pub fn base_url(&self) -> String {
+        let url = match self {
+            Forge::GitHub => "https://github.com".to_string(),
+            Forge::GitHubEnterprise { url } | Forge::GitLab { url } | Forge::Git { url } => {
+                url.clone()
+            }
+            Forge::File { path } => format!("file://{}", path.display()),
+        };
+        url.trim_end_matches('/').to_string()
+    }
+
+    /// URL of the REST API of the forge, if it is GitHub-compatible.
+    pub fn api_url(&self) -> Option<String> {
+        match self {
+            Forge::GitHub => Some("https://api.github.com".to_string()),
+            Forge::GitHubEnterprise { .. } => Some(format!("{}/api/v3", self.base_url())),
+            Forge::GitLab { .. } | Forge::Git { .. } | Forge::File { .. } => None,
+        }
+    }
+
+    /// Git URL of the given `<org>/<repo>` repository.
+    pub fn repo_url(&self, repo: &str) -> String {
+        format!("{}/{repo}", self.base_url())
+    }
+
+    /// Web URL of a commit, if the forge has a web interface.
+    pub fn commit_url(&self, repo: &str, sha: &str) -> Option<String> {
+        let base = self.base_url();
+        match self {
+            Forge::GitHub | Forge::GitHubEnterprise { .. } => {
+                Some(format!("{base}/{repo}/commit/{sha}"))
+            }
+            Forge::GitLab { .. } => Some(format!("{base}/{repo}/-/commit/{sha}")),
+            Forge::Git { .. } | Forge::File { .. } => None,
+        }
+    }
+
+    /// Web URL showing the changes between two commits, if the forge has a web interface.
+    pub fn compare_url(&self, repo: &str, from: &str, to: &str) -> Option<String> {
+        let base = self.base_url();
+        match self {
+            Forge::GitHub | Forge::GitHubEnterprise { .. } => {
+                Some(format!("{base}/{repo}/compare/{from}...{to}"))
+            }
+            Forge::GitLab { .. } => Some(format!("{base}/{repo}/-/compare/{from}...{to}")),
+            Forge::Git { .. } | Forge::File { .. } => None,
+        }
+    }
+
+    /// Web URL that opens a pre-filled pull request from `branch` of `fork` against `repo`,
+    /// if the forge supports it.
+    pub fn new_pull_request_url(
+        &self,
+        repo: &str,
+        fork: &str,
+        branch: &str,
+        title: &str,
+        body: &str,
+    ) -> Option<String> {
+        let base = self.base_url();
+        let title = urlencoding::encode(title);
+        let body = urlencoding::encode(body);
+        match self {
+            Forge::GitHub | Forge::GitHubEnterprise { .. } => {
+                let owner = fork.split('/').next().unwrap_or(fork);
+                Some(format!(
+                    "{base}/{repo}/compare/{owner}:{branch}?quick_pull=1&title={title}&body={body}"
+                ))
+            }
+            Forge::GitLab { .. } => Some(format!(
+                "{base}/{fork}/-/merge_requests/new?\
+                 merge_request%5Bsource_branch%5D={branch}&\
+                 merge_request%5Btitle%5D={title}&\
+                 merge_request%5Bdescription%5D={body}"
+            )),
+            Forge::Git { .. } | Forge::File { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn github_urls() {
+        let forge = Forge::GitHub;
+        assert_eq!(
+            forge.repo_url("rust-lang/rust"),
+            "https://github.com/rust-lang/rust"
+        );
+        assert_eq!(
+            forge.compare_url("rust-lang/rust", "a", "b").as_deref(),
+            Some("https://github.com/rust-lang/rust/compare/a...b")
+        );
+        assert_eq!(
+            forge
+                .new_pull_request_url("rust-lang/rust", "user/rust", "sync", "a b", "c")
+                .as_deref(),
+            Some(
+                "https://github.com/rust-lang/rust/compare/user:sync?quick_pull=1&title=a%20b&body=c"
+            )
+        );
+    }
+
+    #[test]
+    fn gitlab_urls() {
+        let forge = Forge::GitLab {
+            url: "https://gitlab.example.com/".to_string(),
+        };
+        assert_eq!(
+            forge.repo_url("mirrors/rust"),
+            "https://gitlab.example.com/mirrors/rust"
+        );
+        assert_eq!(
+            forge.commit_url("mirrors/rust", "abc").as_deref(),
+            Some("https://gitlab.example.com/mirrors/rust/-/commit/abc")
+        );
+    }
+
+    #[test]
+    fn file_urls() {
+        let forge = Forge::File {
+            path: PathBuf::from("/tmp/repos"),
+        };
+        assert_eq!(
+            forge.repo_url("rust-lang/rust"),
+            "file:///tmp/repos/rust-lang/rust"
+        );
+        assert_eq!(forge.compare_url("rust-lang/rust", "a", "b"), None);
+    }
+
+    #[test]
+    fn parse_forge() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            forge: Forge,
+        }
+        let parse = |s: &str| toml::from_str::<Config>(s).unwrap().forge;
+        assert_eq!(parse("[forge]\nkind = \"github\""), Forge::GitHub);
+        assert_eq!(
+            parse("[forge]\nkind = \"gitlab\""),
+            Forge::GitLab {
+                url: "https://gitlab.com".to_string()
+            }
+        );
+        assert_eq!(
+            parse("[forge]\nkind = \"file\"\npath = \"/srv/git\""),
+            Forge::File {
+                path: PathBuf::from("/srv/git")
+            }
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Read-only queries of the local git repository.
+//!
+//! The sync logic asks a [`GitBackend`] about the state of the repository. By default, the
+//! queries are answered in-process by [`GixBackend`], which does not spawn `git` processes and is
+//! not affected by the user's git config. Merges, commits, fetches and pushes still use the git
+//! CLI through a [`CommandRunner`].
+use crate::command::CommandRunner;
+use crate::utils::is_hermetic_git;
+use anyhow::Context;
+use gix::open::Permissions;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub trait GitBackend: Send + Sync {
+    /// Returns the SHA of the `HEAD` commit.
+    fn head_sha(&self) -> anyhow::Result<String>;
+
+    /// Returns the number of commits reachable from `HEAD` that have no parents.
+    fn count_root_commits(&self) -> anyhow::Result<u32>;
+
+    /// Returns true if there are no changes to tracked files, neither staged nor unstaged.
+    /// Untracked files are ignored.
+    fn is_clean(&self) -> anyhow::Result<bool>;
+
+    /// Returns true if the tracked files in the working tree do not differ from the `baseline`
+    /// commit.
+    fn has_empty_diff(&self, baseline: &str) -> anyhow::Result<bool>;
+}
+
+/// Answers the queries by running the git CLI.
+pub struct CliBackend {
+    runner: Arc<dyn CommandRunner>,
+}
+
+impl CliBackend {
+    pub fn new(runner: Arc<dyn CommandRunner>) -> Self {
+        Self { runner }
+    }
+}
+
+impl GitBackend for CliBackend {
+    fn head_sha(&self) -> anyhow::Result<String> {
+        self.runner.run(&["git", "rev-parse", "HEAD"])
+    }
+
+    fn count_root_commits(&self) -> anyhow::Result<u32> {
+        Ok(self
+            .runner
+            .run(&["git", "rev-list", "HEAD", "--max-parents=0", "--count"])?
+            .parse()?)
+    }
+
+    fn is_clean(&self) -> anyhow::Result<bool> {
+        Ok(self
+            .runner
+            .run(&["git", "status", "--untracked-files=no", "--porcelain"])?
+            .is_empty())
+    }
+
+    fn has_empty_diff(&self, baseline: &str) -> anyhow::Result<bool> {
+        // `git diff --exit-code` "succeeds" if the diff is empty.
+        let (code, _) =
+            self.runner
+                .run_with_exit_code(&["git", "diff", "--exit-code", baseline])?;
+        match code {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => Err(anyhow::anyhow!(
+                "cannot diff against {baseline} (exit code {code:?})"
+            )),
+        }
+    }
+}
+
+/// Answers the queries in-process using `gix`.
+pub struct GixBackend {
+    /// A directory inside of the repository.
+    workdir: PathBuf,
+}
+
+impl GixBackend {
+    pub fn new(workdir: PathBuf) -> Self {
+        Self { workdir }
+    }
+
+    /// Uses the repository in the current directory.
+    pub fn current_dir() -> Self {
+        Self::new(PathBuf::from("."))
+    }
+
+    /// Opens the repository for a single query, so that the state is never stale.
+    fn open(&self) -> anyhow::Result<gix::Repository> {
+        let repo = if is_hermetic_git() {
+            // Ignore the global and system git config, like the git commands do.
+            let options = |permissions: Permissions| {
+                gix::open::Options::default().permissions(Permissions {
+                    config: gix::open::permissions::Config {
+                        system: false,
+                        git: false,
+                        user: false,
+                        ..permissions.config
+                    },
+                    ..permissions
+                })
+            };
+            let trust_map = gix::sec::trust::Mapping {
+                full: options(Permissions::all()),
+                reduced: options(Permissions::secure()),
+            };
+            gix::ThreadSafeRepository::discover_opts(&self.workdir, Default::default(), trust_map)
+                .map(Into::into)
+        } else {
+            gix::discover(&self.workdir)
+        };
+        repo.with_context(|| {
+            format!(
+                "cannot open the git repository at {}",
+                self.workdir.display()
+            )
+        })
+    }
+}
+
+impl GitBackend for GixBackend {
+    fn head_sha(&self) -> anyhow::Result<String> {
+        Ok(self.open()?.head_id()?.to_string())
+    }
+
+    fn count_root_commits(&self) -> anyhow::Result<u32> {
+        let repo = self.open()?;
+        let mut roots = 0;
+        for commit in repo.rev_walk([repo.head_id()?]).all()? {
+            if commit?.parent_ids.is_empty() {
+                roots += 1;
+            }
+        }
+        Ok(roots)
+    }
+
+    fn is_clean(&self) -> anyhow::Result<bool> {
+        Ok(!self.open()?.is_dirty()?)
+    }
+
+    fn has_empty_diff(&self, baseline: &str) -> anyhow::Result<bool> {
+        let repo = self.open()?;
+        let baseline_tree = repo
+            .rev_parse_single(baseline)?
+            .object()?
+            .peel_to_tree()?
+            .id;
+        Ok(baseline_tree == repo.head_tree_id()? && !repo.is_dirty()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::SystemRunner;
+    use crate::utils::run_command_at;
+    use std::path::Path;
+
+    fn git(repo: &Path, args: &[&str]) -> String {
+        let mut command = vec![
+            "git",
+            "-c",
+            "user.name=test",
+            "-c",
+            "user.email=test@example.com",
+        ];
+        command.extend(args);
+        run_command_at(command, repo, false).unwrap()
+    }
+
+    /// Both backends have to give the same answers.
+    #[test]
+    fn backends_agree() {
+        let repo = std::env::temp_dir().join(format!("josh-sync-git-test-{}", std::process::id()));
+        std::fs::create_dir_all(&repo).unwrap();
+        git(&repo, &["init", "--initial-branch=main"]);
+        std::fs::write(repo.join("a.txt"), "a").unwrap();
+        git(&repo, &["add", "a.txt"]);
+        git(&repo, &["commit", "-m", "first"]);
+        let first = git(&repo, &["rev-parse", "HEAD"]);
+        git(&repo, &["commit", "--allow-empty", "-m", "empty"]);
+        let empty = git(&repo, &["rev-parse", "HEAD"]);
+        // A second root commit
+        git(&repo, &["checkout", "--orphan", "other"]);
+        git(&repo, &["commit", "-m", "other root"]);
+        git(&repo, &["checkout", "main"]);
+        git(&repo, &["merge", "--allow-unrelated-histories", "other"]);
+
+        let cli = CliBackend::new(Arc::new(TestRunner(repo.clone())));
+        let gix = GixBackend::new(repo.clone());
+        let backends: [&dyn GitBackend; 2] = [&cli, &gix];
+        for backend in backends {
+            assert_eq!(
+                backend.head_sha().unwrap(),
+                git(&repo, &["rev-parse", "HEAD"])
+            );
+            assert_eq!(backend.count_root_commits().unwrap(), 2);
+            assert!(backend.is_clean().unwrap());
+            assert!(backend.has_empty_diff(&empty).unwrap());
+            assert!(backend.has_empty_diff(&first).unwrap());
+        }
+
+        // Modify a tracked file
+        std::fs::write(repo.join("a.txt"), "b").unwrap();
+        // Untracked files are ignored
+        std::fs::write(repo.join("untracked.txt"), "c").unwrap();
+        for backend in backends {
+            assert!(!backend.is_clean().unwrap());
+            assert!(!backend.has_empty_diff(&first).unwrap());
+        }
+        git(&repo, &["add", "a.txt"]);
+        for backend in backends {
+            assert!(!backend.is_clean().unwrap());
+        }
+        git(&repo, &["commit", "-m", "change"]);
+        for backend in backends {
+            assert!(backend.is_clean().unwrap());
+            assert!(!backend.has_empty_diff(&first).unwrap());
+        }
+
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    /// Runs the git commands of [`CliBackend`] in the test repository.
+    struct TestRunner(PathBuf);
+
+    impl TestRunner {
+        fn in_repo<'a>(&'a self, args: &[&'a str]) -> Vec<&'a str> {
+            assert_eq!(args[0], "git");
+            let mut command = vec!["git", "-C", self.0.to_str().unwrap()];
+            command.extend(&args[1..]);
+            command
+        }
+    }
+
+    impl CommandRunner for TestRunner {
+        fn run_at(&self, args: &[&str], _workdir: Option<&Path>) -> anyhow::Result<String> {
+            SystemRunner::new(false).run(&self.in_repo(args))
+        }
+
+        fn stream(&self, args: &[&str]) -> anyhow::Result<()> {
+            SystemRunner::new(false).stream(&self.in_repo(args))
+        }
+
+        fn run_with_exit_code(&self, args: &[&str]) -> anyhow::Result<(Option<i32>, String)> {
+            SystemRunner::new(false).run_with_exit_code(&self.in_repo(args))
+        }
+    }
+}
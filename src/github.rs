@@ -0,0 +1,415 @@
+//! A minimal client of the GitHub REST API, used to create and update pull requests.
+
+use crate::config::PullRequestConfig;
+use crate::forge::Forge;
+use crate::utils::run_command;
+use anyhow::Context;
+use serde_json::json;
+
+/// A pull request as returned by the GitHub API.
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PullRequest {
+    pub number: u64,
+    pub html_url: String,
+    pub title: String,
+    pub user: User,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct User {
+    pub login: String,
+}
+
+/// Parameters of a pull request that should be created or updated.
+pub struct SyncPullRequest<'a> {
+    /// `<org>/<repo>` of the repository in which the pull request is opened.
+    pub repo: &'a str,
+    pub title: &'a str,
+    pub body: &'a str,
+    /// Branch with the changes, possibly prefixed with `<owner>:` if it lives in a fork.
+    pub head: &'a str,
+    /// Branch into which the changes should be merged.
+    /// If not set, the default branch of the repository is used.
+    pub base: Option<&'a str>,
+    /// Login of the author of the pull request, used to find an existing pull request.
+    /// If not set, the authenticated user is used.
+    pub author: Option<&'a str>,
+}
+
+/// Outcome of [`GitHubClient::create_or_update_pr`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum PullRequestOutcome {
+    Created(PullRequest),
+    Updated(PullRequest),
+}
+
+impl PullRequestOutcome {
+    pub fn pull_request(&self) -> &PullRequest {
+        match self {
+            PullRequestOutcome::Created(pr) | PullRequestOutcome::Updated(pr) => pr,
+        }
+    }
+}
+
+pub struct GitHubClient {
+    api_url: String,
+    token: String,
+    agent: ureq::Agent,
+}
+
+impl GitHubClient {
+    pub fn new(api_url: &str, token: String) -> Self {
+        Self {
+            api_url: api_url.trim_end_matches('/').to_string(),
+            token,
+            agent: ureq::AgentBuilder::new()
+                .user_agent(concat!("rustc-josh-sync/", env!("CARGO_PKG_VERSION")))
+                .build(),
+        }
+    }
+
+    /// Creates a client for the API of `forge`, authenticated using the `GITHUB_TOKEN` or
+    /// `GH_TOKEN` environment variable, or the token of the `gh` tool.
+    pub fn for_forge(forge: &Forge, verbose: bool) -> anyhow::Result<Self> {
+        let api_url = forge.api_url().ok_or_else(|| {
+            anyhow::anyhow!("pull requests can only be created on GitHub, not on {forge:?}")
+        })?;
+        let token = ["GITHUB_TOKEN", "GH_TOKEN"]
+            .into_iter()
+            .find_map(|var| std::env::var(var).ok().filter(|token| !token.is_empty()));
+        let token = match token {
+            Some(token) => token,
+            None => run_command(["gh", "auth", "token"], verbose).context(
+                "cannot find a GitHub token. Set the GITHUB_TOKEN environment variable or log in using `gh auth login`",
+            )?,
+        };
+        Ok(Self::new(&api_url, token))
+    }
+
+    /// Returns the login of the authenticated user.
+    pub fn current_user(&self) -> anyhow::Result<String> {
+        let user: User = self.request("GET", "/user", None)?;
+        Ok(user.login)
+    }
+
+    /// Returns the name of the default branch of `repo`.
+    pub fn default_branch(&self, repo: &str) -> anyhow::Result<String> {
+        #[derive(serde::Deserialize)]
+        struct Repository {
+            default_branch: String,
+        }
+        let repository: Repository = self.request("GET", &format!("/repos/{repo}"), None)?;
+        Ok(repository.default_branch)
+    }
+
+    /// Finds an open pull request with the title, head and base (if set) of `pr`, opened by
+    /// `author`.
+    pub fn find_open_pr(
+        &self,
+        pr: &SyncPullRequest,
+        author: &str,
+    ) -> anyhow::Result<Option<PullRequest>> {
+        let repo = pr.repo;
+        // GitHub filters by head only if it includes the owner of the branch. Filtering on the
+        // server means that we do not need to page through all open pull requests.
+        let head = if pr.head.contains(':') {
+            pr.head.to_string()
+        } else {
+            let owner = repo.split_once('/').map_or(repo, |(owner, _)| owner);
+            format!("{owner}:{}", pr.head)
+        };
+        let mut path = format!(
+            "/repos/{repo}/pulls?state=open&head={}&per_page=100",
+            urlencoding::encode(&head)
+        );
+        if let Some(base) = pr.base {
+            path.push_str(&format!("&base={}", urlencoding::encode(base)));
+        }
+        let prs: Vec<PullRequest> = self.request("GET", &path, None)?;
+        Ok(prs.into_iter().find(|existing| {
+            existing.title == pr.title && existing.user.login.eq_ignore_ascii_case(author)
+        }))
+    }
+
+    /// Creates a new pull request.
+    pub fn create_pr(&self, pr: &SyncPullRequest) -> anyhow::Result<PullRequest> {
+        let repo = pr.repo;
+        let base = match pr.base {
+            Some(base) => base.to_string(),
+            None => self.default_branch(repo)?,
+        };
+        self.request(
+            "POST",
+            &format!("/repos/{repo}/pulls"),
+            Some(json!({
+                "title": pr.title,
+                "body": pr.body,
+                "head": pr.head,
+                "base": base,
+            })),
+        )
+    }
+
+    /// Creates a new pull request, or updates the description of an open pull request with the
+    /// same title, author, head and base. Labels and reviewers from `config` are applied to new pull
+    /// requests, labels are also (re-)added to updated ones.
+    pub fn create_or_update_pr(
+        &self,
+        pr: &SyncPullRequest,
+        config: &PullRequestConfig,
+    ) -> anyhow::Result<PullRequestOutcome> {
+        let repo = pr.repo;
+        let author = match pr.author {
+            Some(author) => author.to_string(),
+            None => self
+                .current_user()
+                .context("cannot determine the authenticated GitHub user")?,
+        };
+        let outcome = match self.find_open_pr(pr, &author)? {
+            Some(existing) => {
+                let updated = self.request(
+                    "PATCH",
+                    &format!("/repos/{repo}/pulls/{}", existing.number),
+                    Some(json!({ "body": pr.body })),
+                )?;
+                PullRequestOutcome::Updated(updated)
+            }
+            None => PullRequestOutcome::Created(self.create_pr(pr)?),
+        };
+
+        let number = outcome.pull_request().number;
+        if !config.labels.is_empty() {
+            self.request::<serde_json::Value>(
+                "POST",
+                &format!("/repos/{repo}/issues/{number}/labels"),
+                Some(json!({ "labels": config.labels })),
+            )
+            .context("cannot add labels to the pull request")?;
+        }
+        if matches!(outcome, PullRequestOutcome::Created(_)) && !config.reviewers.is_empty() {
+            // Team reviewers are written as `<org>/<team>`
+            let (teams, users): (Vec<&str>, Vec<&str>) = config
+                .reviewers
+                .iter()
+                .map(String::as_str)
+                .partition(|reviewer| reviewer.contains('/'));
+            let teams = teams
+                .into_iter()
+                .map(|team| team.rsplit_once('/').unwrap().1)
+                .collect::<Vec<_>>();
+            self.request::<serde_json::Value>(
+                "POST",
+                &format!("/repos/{repo}/pulls/{number}/requested_reviewers"),
+                Some(json!({ "reviewers": users, "team_reviewers": teams })),
+            )
+            .context("cannot request reviewers of the pull request")?;
+        }
+        Ok(outcome)
+    }
+
+    fn request<T: serde::de::DeserializeOwned>(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> anyhow::Result<T> {
+        let request = self
+            .agent
+            .request(method, &format!("{}{path}", self.api_url))
+            .set("Accept", "application/vnd.github+json")
+            .set("Authorization", &format!("Bearer {}", self.token))
+            .set("X-GitHub-Api-Version", "2022-11-28");
+        let response = match body {
+            Some(body) => request.send_json(body),
+            None => request.call(),
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(ureq::Error::Status(status, response)) => {
+                let message = response.into_string().unwrap_or_default();
+                return Err(anyhow::anyhow!(
+                    "GitHub API request `{method} {path}` failed with status {status}: {message}"
+                ));
+            }
+            Err(error) => {
+                return Err(anyhow::Error::new(error)
+                    .context(format!("GitHub API request `{method} {path}` failed")));
+            }
+        };
+        response
+            .into_json()
+            .with_context(|| format!("cannot parse response of `{method} {path}`"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    /// Serves the given responses to consecutive requests, and returns the received requests
+    /// as `<method> <path> <body>`.
+    fn mock_server(responses: Vec<(u16, serde_json::Value)>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut requests = vec![];
+            for (status, response) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let mut parts = request_line.split_whitespace();
+                requests.push(format!(
+                    "{} {} {}",
+                    parts.next().unwrap(),
+                    parts.next().unwrap(),
+                    String::from_utf8(body).unwrap()
+                ));
+
+                let response = response.to_string();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {status} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+                    response.len()
+                )
+                .unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    fn pr_json(number: u64, title: &str, author: &str) -> serde_json::Value {
+        json!({
+            "number": number,
+            "html_url": format!("https://github.com/rust-lang/stdarch/pull/{number}"),
+            "title": title,
+            "user": { "login": author },
+        })
+    }
+
+    fn sync_pr() -> SyncPullRequest<'static> {
+        SyncPullRequest {
+            repo: "rust-lang/stdarch",
+            title: "Rustc pull update",
+            body: "Latest update from rustc.",
+            head: "rustc-pull",
+            base: Some("master"),
+            author: Some("bot[bot]"),
+        }
+    }
+
+    #[test]
+    fn create_pr() {
+        let (url, server) = mock_server(vec![
+            (200, json!([pr_json(1, "Other PR", "bot[bot]")])),
+            (201, pr_json(2, "Rustc pull update", "bot[bot]")),
+            (200, json!([])),
+            (201, json!({})),
+        ]);
+        let config = PullRequestConfig {
+            labels: vec!["S-waiting-on-review".to_string()],
+            reviewers: vec!["alice".to_string(), "rust-lang/libs".to_string()],
+        };
+        let outcome = GitHubClient::new(&url, "token".to_string())
+            .create_or_update_pr(&sync_pr(), &config)
+            .unwrap();
+        assert_eq!(
+            outcome,
+            PullRequestOutcome::Created(
+                serde_json::from_value(pr_json(2, "Rustc pull update", "bot[bot]")).unwrap()
+            )
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "GET /repos/rust-lang/stdarch/pulls?state=open&head=rust-lang%3Arustc-pull&per_page=100&base=master ",
+                r#"POST /repos/rust-lang/stdarch/pulls {"base":"master","body":"Latest update from rustc.","head":"rustc-pull","title":"Rustc pull update"}"#,
+                r#"POST /repos/rust-lang/stdarch/issues/2/labels {"labels":["S-waiting-on-review"]}"#,
+                r#"POST /repos/rust-lang/stdarch/pulls/2/requested_reviewers {"reviewers":["alice"],"team_reviewers":["libs"]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn update_existing_pr() {
+        let (url, server) = mock_server(vec![
+            (200, json!({ "login": "bot[bot]" })),
+            (200, json!([pr_json(3, "Rustc pull update", "bot[bot]")])),
+            (200, pr_json(3, "Rustc pull update", "bot[bot]")),
+        ]);
+        let pr = SyncPullRequest {
+            head: "bot:rustc-pull",
+            base: None,
+            author: None,
+            ..sync_pr()
+        };
+        let outcome = GitHubClient::new(&url, "token".to_string())
+            .create_or_update_pr(&pr, &PullRequestConfig::default())
+            .unwrap();
+        assert!(matches!(outcome, PullRequestOutcome::Updated(pr) if pr.number == 3));
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "GET /user ",
+                "GET /repos/rust-lang/stdarch/pulls?state=open&head=bot%3Arustc-pull&per_page=100 ",
+                r#"PATCH /repos/rust-lang/stdarch/pulls/3 {"body":"Latest update from rustc."}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn create_pr_from_fork() {
+        let (url, server) = mock_server(vec![
+            (200, json!({ "default_branch": "main" })),
+            (201, pr_json(4, "stdarch subtree update", "user")),
+        ]);
+        let pr = GitHubClient::new(&url, "token".to_string())
+            .create_pr(&SyncPullRequest {
+                repo: "rust-lang/rust",
+                title: "stdarch subtree update",
+                body: "r? @ghost",
+                head: "user:sync",
+                base: None,
+                author: None,
+            })
+            .unwrap();
+        assert_eq!(pr.number, 4);
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "GET /repos/rust-lang/rust ",
+                r#"POST /repos/rust-lang/rust/pulls {"base":"main","body":"r? @ghost","head":"user:sync","title":"stdarch subtree update"}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn report_api_errors() {
+        let (url, server) = mock_server(vec![(403, json!({ "message": "Forbidden" }))]);
+        let error = GitHubClient::new(&url, "token".to_string())
+            .create_or_update_pr(&sync_pr(), &PullRequestConfig::default())
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("status 403"));
+        server.join().unwrap();
+    }
+}
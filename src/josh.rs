@@ -1,109 +1,369 @@
+use crate::command::{CommandRunner, SystemRunner};
 use crate::config::JoshConfig;
-use crate::utils::{is_null_sha, run_command, run_command_by_path};
+use crate::utils::{is_null_sha, run_command_by_path};
 use anyhow::Context;
-use std::net::{SocketAddr, TcpStream};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-const JOSH_PORT: u16 = 42042;
+/// How long we wait for josh-proxy to start by default, in seconds.
+const DEFAULT_JOSH_STARTUP_TIMEOUT: u64 = 30;
 /// Version of `josh-proxy` that should be downloaded for the user.
-const JOSH_VERSION: &str = "r26.06.11";
+pub const JOSH_VERSION: &str = "r26.06.11";
 
+#[derive(Clone)]
 pub struct JoshProxy {
     path: PathBuf,
+    runner: Arc<dyn CommandRunner>,
+    /// Port of a proxy that is already running, which is used instead of starting one.
+    running_port: Option<u16>,
 }
 
 pub struct JoshFilter {
     path: PathBuf,
+    runner: Arc<dyn CommandRunner>,
 }
 
 impl JoshProxy {
     pub fn from_path(path: PathBuf) -> Self {
-        Self { path }
+        Self {
+            path,
+            runner: Arc::new(SystemRunner::default()),
+            running_port: None,
+        }
+    }
+
+    /// Uses a josh-proxy that is already listening on `port`, instead of starting one.
+    /// It is not stopped by josh-sync.
+    pub fn already_running(port: u16) -> Self {
+        Self {
+            running_port: Some(port),
+            ..Self::from_path(PathBuf::from("josh-proxy"))
+        }
     }
 
-    /// Tries to figure out if `josh-proxy` is installed.
-    pub fn lookup() -> Option<Self> {
-        which::which("josh-proxy").ok().map(|path| Self { path })
+    /// Finds `josh-proxy` with the expected version, installing it if needed.
+    pub fn find_or_install(verbose: bool) -> anyhow::Result<Self> {
+        let path = JoshTool::Proxy.find_or_install(verbose)?;
+        Ok(Self::from_path(path).with_runner(Arc::new(SystemRunner::new(verbose))))
+    }
+
+    /// Uses `runner` for the commands that manage the proxy process.
+    pub fn with_runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
     }
 
     pub fn start(&self, config: &JoshConfig) -> anyhow::Result<RunningJoshProxy> {
-        // Determine cache directory.
-        let user_dirs =
-            directories::ProjectDirs::from("org", &config.full_repo_name(), "rustc-josh")
-                .context("cannot determine cache directory for Josh")?;
-        let local_dir = user_dirs.cache_dir().to_owned();
+        if let Some(port) = self.running_port {
+            return Ok(RunningJoshProxy {
+                process: None,
+                port,
+                log_path: None,
+                remove_log: false,
+                runner: self.runner.clone(),
+            });
+        }
 
-        // Start josh, silencing its output.
+        let local_dir = josh_cache_dir(config)?;
+
+        let port = match config.josh_port {
+            Some(port) => {
+                if is_port_open(port) {
+                    return Err(anyhow::anyhow!(
+                        "port {port} is already in use (maybe by a stale josh-proxy instance?)"
+                    ));
+                }
+                port
+            }
+            None => find_free_port().context("cannot find a free port for josh-proxy")?,
+        };
+
+        // Start josh, redirecting its output to a log file.
+        std::fs::create_dir_all(&local_dir)
+            .with_context(|| format!("cannot create cache directory {}", local_dir.display()))?;
+        let log_path = local_dir.join(format!("josh-proxy-{port}.log"));
+        let log = std::fs::File::create(&log_path)
+            .with_context(|| format!("cannot create josh-proxy log {}", log_path.display()))?;
         let josh = std::process::Command::new(&self.path)
             .arg("--local")
-            .arg(local_dir)
+            .arg(&local_dir)
             .args([
-                "--remote=https://github.com",
-                &format!("--port={JOSH_PORT}"),
+                &format!("--remote={}", config.forge.base_url()),
+                &format!("--port={port}"),
                 "--no-background",
             ])
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
             .spawn()
             .context("failed to start josh-proxy, make sure it is installed")?;
+        let pid = josh.id();
+        // From now on, the process is stopped on drop, also if we fail to connect to it.
+        let mut josh = RunningJoshProxy {
+            process: Some(josh),
+            port,
+            log_path: Some(log_path),
+            remove_log: false,
+            runner: self.runner.clone(),
+        };
+
+        // Wait until josh responds to HTTP requests. We try every 10ms until the timeout passes.
+        let timeout = Duration::from_secs(
+            config
+                .josh_startup_timeout
+                .unwrap_or(DEFAULT_JOSH_STARTUP_TIMEOUT),
+        );
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if let Some(process) = &mut josh.process
+                && let Some(status) = process.try_wait()?
+            {
+                return Err(anyhow::anyhow!(
+                    "josh-proxy exited with {status} before it started listening on port {port}\n{}",
+                    josh.log_tail()
+                ));
+            }
 
-        // Wait until the port is open. We try every 10ms until 1s passed.
-        for _ in 0..100 {
-            // This will generally fail immediately when the port is still closed.
-            let addr = SocketAddr::from(([127, 0, 0, 1], JOSH_PORT));
-            let josh_ready = TcpStream::connect_timeout(&addr, Duration::from_millis(1));
-
-            if josh_ready.is_ok() {
-                println!("josh up and running");
-                return Ok(RunningJoshProxy {
-                    process: josh,
-                    port: JOSH_PORT,
-                });
+            if is_port_open(port) {
+                // Make sure that we talk to our own proxy, and not some other process
+                // that has grabbed the port in the meantime.
+                if listening_process_matches(pid, port) == Some(false) {
+                    return Err(anyhow::anyhow!(
+                        "port {port} is used by a different process than the spawned josh-proxy"
+                    ));
+                }
+                if responds_to_http(port) {
+                    eprintln!("josh up and running on port {port}");
+                    return Ok(josh);
+                }
             }
 
             // Not ready yet.
             std::thread::sleep(Duration::from_millis(10));
         }
-        panic!("Even after waiting for 1s, josh-proxy is still not available.");
-    }
-}
-
-/// Try to install (or update) josh-proxy, to make sure that we use the correct version.
-pub fn try_install_josh(verbose: bool) -> Option<JoshProxy> {
-    run_command(
-        &[
-            "cargo",
-            "+stable",
-            "install",
-            "--locked",
-            "--git",
-            "https://github.com/josh-project/josh",
-            "--tag",
-            JOSH_VERSION,
-            "josh-proxy",
-        ],
-        verbose,
-    )
-    .expect("cannot install josh-proxy");
-    JoshProxy::lookup()
+        Err(anyhow::anyhow!(
+            "Even after waiting for {}s, josh-proxy is still not available. \
+            You can increase the timeout using `josh-startup-timeout` in the config.\n{}",
+            timeout.as_secs(),
+            josh.log_tail()
+        ))
+    }
+}
+
+/// Sends a HTTP request to the josh-proxy version endpoint, and checks that we receive
+/// a HTTP response.
+fn responds_to_http(port: u16) -> bool {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let Ok(mut stream) = TcpStream::connect_timeout(&addr, Duration::from_millis(100)) else {
+        return false;
+    };
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+    if stream
+        .write_all(b"GET /version HTTP/1.0\r\nHost: localhost\r\n\r\n")
+        .is_err()
+    {
+        return false;
+    }
+    let mut response = [0; 5];
+    stream.read_exact(&mut response).is_ok() && &response == b"HTTP/"
+}
+
+pub(crate) fn is_port_open(port: u16) -> bool {
+    // This will generally fail immediately when the port is closed.
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    TcpStream::connect_timeout(&addr, Duration::from_millis(1)).is_ok()
+}
+
+/// Asks the OS for a free ephemeral port.
+fn find_free_port() -> std::io::Result<u16> {
+    let listener = TcpListener::bind(("127.0.0.1", 0))?;
+    Ok(listener.local_addr()?.port())
+}
+
+/// Checks if the process with the given `pid` is the one listening on `port`.
+/// Returns `None` if this cannot be determined.
+#[cfg(target_os = "linux")]
+fn listening_process_matches(pid: u32, port: u16) -> Option<bool> {
+    // Find the inodes of sockets listening on the port.
+    let mut inodes = vec![];
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let Ok(content) = std::fs::read_to_string(table) else {
+            continue;
+        };
+        for line in content.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // Fields: sl, local_address, rem_address, st, ..., inode (10th field)
+            let (Some(local_address), Some(state), Some(inode)) =
+                (fields.get(1), fields.get(3), fields.get(9))
+            else {
+                continue;
+            };
+            let local_port = local_address
+                .rsplit_once(':')
+                .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+            // 0A is the LISTEN state
+            if local_port == Some(port) && *state == "0A" {
+                inodes.push(format!("socket:[{inode}]"));
+            }
+        }
+    }
+    if inodes.is_empty() {
+        return None;
+    }
+
+    // Check if one of the sockets belongs to the process.
+    let fds = std::fs::read_dir(format!("/proc/{pid}/fd")).ok()?;
+    Some(fds.filter_map(|fd| fd.ok()).any(|fd| {
+        std::fs::read_link(fd.path()).is_ok_and(|target| {
+            inodes
+                .iter()
+                .any(|inode| target.as_os_str() == inode.as_str())
+        })
+    }))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn listening_process_matches(_pid: u32, _port: u16) -> Option<bool> {
+    None
+}
+
+/// A binary from the Josh project that is used by josh-sync.
+#[derive(Clone, Copy, Debug)]
+pub enum JoshTool {
+    Proxy,
+    Filter,
+}
+
+impl JoshTool {
+    pub fn binary_name(self) -> &'static str {
+        match self {
+            JoshTool::Proxy => "josh-proxy",
+            JoshTool::Filter => "josh-filter",
+        }
+    }
+
+    /// Name of the crate that contains the binary.
+    fn crate_name(self) -> &'static str {
+        match self {
+            JoshTool::Proxy => "josh-proxy",
+            // The josh-filter binary is included in the josh-cli crate
+            JoshTool::Filter => "josh-cli",
+        }
+    }
+
+    /// Path of the binary in the josh-sync private installation directory.
+    pub fn private_path(self) -> anyhow::Result<PathBuf> {
+        Ok(josh_install_dir()?.join("bin").join(format!(
+            "{}{}",
+            self.binary_name(),
+            std::env::consts::EXE_SUFFIX
+        )))
+    }
+
+    /// Finds a binary of the tool with the version that we expect.
+    /// Binaries installed by josh-sync are preferred over binaries from `PATH`.
+    pub fn find(self, verbose: bool) -> Option<PathBuf> {
+        if let Ok(path) = self.private_path()
+            && installed_version(&path, verbose).is_some()
+        {
+            return Some(path);
+        }
+        let path = which::which(self.binary_name()).ok()?;
+        installed_version(&path, verbose)
+            .is_some_and(|version| version.contains(JOSH_VERSION))
+            .then_some(path)
+    }
+
+    /// Installs the tool into the josh-sync private installation directory.
+    pub fn install(self, runner: &dyn CommandRunner) -> anyhow::Result<PathBuf> {
+        let root = josh_install_dir()?;
+        eprintln!(
+            "Installing {} {JOSH_VERSION} into {}...",
+            self.binary_name(),
+            root.display()
+        );
+        // Building Josh takes a while, so show the progress of cargo (on stderr, to keep stdout
+        // clean for the results of the command).
+        runner
+            .stream(&[
+                "cargo",
+                "+stable",
+                "install",
+                "--locked",
+                "--root",
+                &root.to_string_lossy(),
+                "--git",
+                "https://github.com/josh-project/josh",
+                "--tag",
+                JOSH_VERSION,
+                self.crate_name(),
+            ])
+            .with_context(|| format!("cannot install {}", self.binary_name()))?;
+        self.private_path()
+    }
+
+    /// Finds a binary of the tool with the expected version, or installs it.
+    pub fn find_or_install(self, verbose: bool) -> anyhow::Result<PathBuf> {
+        match self.find(verbose) {
+            Some(path) => Ok(path),
+            None => self.install(&SystemRunner::new(verbose)),
+        }
+    }
+}
+
+/// Directory in which josh-proxy keeps its clones of the upstream repository, and the logs of
+/// failed syncs.
+pub fn josh_cache_dir(config: &JoshConfig) -> anyhow::Result<PathBuf> {
+    let dirs = directories::ProjectDirs::from("org", &config.full_repo_name(), "rustc-josh")
+        .context("cannot determine cache directory for Josh")?;
+    Ok(dirs.cache_dir().to_owned())
+}
+
+/// Directory into which josh-sync installs the Josh binaries of the expected version.
+pub fn josh_install_dir() -> anyhow::Result<PathBuf> {
+    let dirs = directories::ProjectDirs::from("org", "rust-lang", "rustc-josh-sync")
+        .context("cannot determine data directory for josh-sync")?;
+    Ok(dirs.data_dir().join(format!("josh-{JOSH_VERSION}")))
+}
+
+/// Returns the output of `<binary> --version`, or `None` if it cannot be executed.
+pub fn installed_version(path: &Path, verbose: bool) -> Option<String> {
+    if !path.is_file() {
+        return None;
+    }
+    let workdir = std::env::current_dir().ok()?;
+    run_command_by_path(path, ["--version"], &workdir, true, verbose).ok()
 }
 
 impl JoshFilter {
-    /// Tries to figure out if `josh-filter` is installed.
-    pub fn lookup() -> Option<Self> {
-        which::which("josh-filter").ok().map(|path| Self { path })
-    }
-
-    pub fn run<'a, Args: AsRef<[&'a str]>>(
-        &self,
-        args: Args,
-        workdir: &Path,
-        verbose: bool,
-    ) -> anyhow::Result<()> {
-        let args = args.as_ref();
-        let output = run_command_by_path(&self.path, args, workdir, true, verbose)?;
+    pub fn from_path(path: PathBuf) -> Self {
+        Self {
+            path,
+            runner: Arc::new(SystemRunner::default()),
+        }
+    }
+
+    /// Finds `josh-filter` with the expected version, installing it if needed.
+    pub fn find_or_install(verbose: bool) -> anyhow::Result<Self> {
+        let path = JoshTool::Filter.find_or_install(verbose)?;
+        Ok(Self::from_path(path).with_runner(Arc::new(SystemRunner::new(verbose))))
+    }
+
+    /// Uses `runner` to execute `josh-filter`.
+    pub fn with_runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    /// Runs `josh-filter` with the given arguments in `workdir` (the current directory if
+    /// `None`).
+    pub fn run(&self, args: &[&str], workdir: Option<&Path>) -> anyhow::Result<()> {
+        let path = self.path.to_string_lossy();
+        let mut command = vec![path.as_ref()];
+        command.extend(args);
+        let output = self.runner.run_at(&command, workdir)?;
         if is_null_sha(&output) {
             return Err(anyhow::anyhow!(
                 "josh-filter returned null SHA, filter may not match any content"
@@ -113,30 +373,15 @@ impl JoshFilter {
     }
 }
 
-pub fn try_install_josh_filter(verbose: bool) -> Option<JoshFilter> {
-    // The josh-filter binary is included in the josh-cli crate
-    run_command(
-        &[
-            "cargo",
-            "+stable",
-            "install",
-            "--locked",
-            "--git",
-            "https://github.com/josh-project/josh",
-            "--tag",
-            JOSH_VERSION,
-            "josh-cli",
-        ],
-        verbose,
-    )
-    .expect("cannot install josh-filter");
-    JoshFilter::lookup()
-}
-
 /// Create a wrapper that represents a running instance of `josh-proxy` and stops it on drop.
 pub struct RunningJoshProxy {
-    process: std::process::Child,
+    /// The spawned process, or `None` if the proxy was already running.
+    process: Option<std::process::Child>,
     port: u16,
+    log_path: Option<PathBuf>,
+    /// Whether the log should be removed on drop, see [`RunningJoshProxy::finish`].
+    remove_log: bool,
+    runner: Arc<dyn CommandRunner>,
 }
 
 impl RunningJoshProxy {
@@ -148,28 +393,60 @@ impl RunningJoshProxy {
             self.port
         )
     }
-}
 
-impl Drop for RunningJoshProxy {
-    fn drop(&mut self) {
+    /// Returns the last lines of the josh-proxy log, to be included in error messages.
+    pub fn log_tail(&self) -> String {
+        const LINES: usize = 20;
+
+        let Some(log_path) = &self.log_path else {
+            return "The josh-proxy log is not available".to_string();
+        };
+        let log = match std::fs::read_to_string(log_path) {
+            Ok(log) => log,
+            Err(error) => {
+                return format!("cannot read josh-proxy log {}: {error}", log_path.display());
+            }
+        };
+        let lines: Vec<&str> = log.lines().collect();
+        let tail = &lines[lines.len().saturating_sub(LINES)..];
+        format!(
+            "Last lines of the josh-proxy log ({}):\n{}",
+            log_path.display(),
+            tail.join("\n")
+        )
+    }
+
+    /// Stops the proxy once the operation that used it has finished. The log is removed if the
+    /// operation has `succeeded`, otherwise it is kept for investigating the failure.
+    pub fn finish(mut self, succeeded: bool) {
+        self.remove_log = succeeded;
+    }
+
+    /// Stops the spawned process, if any.
+    fn stop(&mut self) {
+        let Some(process) = &mut self.process else {
+            return;
+        };
         if cfg!(unix) {
             // Try to gracefully shut it down.
-            Command::new("kill")
-                .args(["-s", "INT", &self.process.id().to_string()])
-                .output()
-                .expect("failed to SIGINT josh-proxy");
+            if let Err(error) = self
+                .runner
+                .run(&["kill", "-s", "INT", &process.id().to_string()])
+            {
+                eprintln!("Failed to SIGINT josh-proxy: {error}");
+            }
             // Sadly there is no "wait with timeout"... so we just give it some time to finish.
             // We try every 10ms until 1s passed.
             for _ in 0..100 {
                 std::thread::sleep(Duration::from_millis(10));
                 // Now hopefully it is gone.
-                if self
-                    .process
-                    .try_wait()
-                    .expect("failed to wait for josh-proxy")
-                    .is_some()
-                {
-                    return;
+                match process.try_wait() {
+                    Ok(Some(_)) => return,
+                    Ok(None) => {}
+                    Err(error) => {
+                        eprintln!("Failed to wait for josh-proxy: {error}");
+                        break;
+                    }
                 }
             }
         }
@@ -178,6 +455,90 @@ impl Drop for RunningJoshProxy {
             "I have to kill josh-proxy the hard way, let's hope this does not \
             break anything."
         );
-        self.process.kill().expect("failed to SIGKILL josh-proxy");
+        if let Err(error) = process.kill() {
+            eprintln!("Failed to SIGKILL josh-proxy: {error}");
+        }
+    }
+}
+
+impl Drop for RunningJoshProxy {
+    fn drop(&mut self) {
+        self.stop();
+        if self.remove_log
+            && let Some(log_path) = &self.log_path
+            && let Err(error) = std::fs::remove_file(log_path)
+        {
+            eprintln!(
+                "Failed to remove josh-proxy log {}: {error}",
+                log_path.display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn detect_listening_process() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(is_port_open(port));
+        assert_eq!(
+            listening_process_matches(std::process::id(), port),
+            Some(true)
+        );
+        drop(listener);
+        assert_eq!(listening_process_matches(std::process::id(), port), None);
+    }
+
+    #[test]
+    fn log_is_kept_after_failure() {
+        let dir = std::env::temp_dir().join(format!("josh-sync-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let proxy = |log_path: &Path| RunningJoshProxy {
+            process: None,
+            port: 42,
+            log_path: Some(log_path.to_path_buf()),
+            remove_log: false,
+            runner: Arc::new(SystemRunner::new(false)),
+        };
+
+        // Returning early with an error keeps the log
+        let failed = dir.join("failed.log");
+        std::fs::write(&failed, "error").unwrap();
+        drop(proxy(&failed));
+        assert!(failed.exists());
+
+        let failed = dir.join("failed-sync.log");
+        std::fs::write(&failed, "error").unwrap();
+        proxy(&failed).finish(false);
+        assert!(failed.exists());
+
+        let succeeded = dir.join("succeeded.log");
+        std::fs::write(&succeeded, "ok").unwrap();
+        proxy(&succeeded).finish(true);
+        assert!(!succeeded.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn http_probe() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            // The first connection is closed without a response, the second one gets one.
+            drop(listener.accept().unwrap());
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 16];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+        });
+        assert!(!responds_to_http(port));
+        assert!(responds_to_http(port));
+        server.join().unwrap();
     }
 }
@@ -1,14 +1,25 @@
-use crate::config::JoshConfig;
+use crate::config::{JoshConfig, SubtreeConfig};
 use std::path::PathBuf;
 
+pub mod changelog;
+pub mod command;
 pub mod config;
+pub mod doctor;
+pub mod error;
+pub mod filter;
+pub mod forge;
+pub mod git;
+pub mod github;
 pub mod josh;
 pub mod sync;
+pub mod template;
 pub mod utils;
 
 #[derive(Clone)]
 pub struct SyncContext {
     pub config: JoshConfig,
+    /// The subtree that is being synchronized.
+    pub subtree: SubtreeConfig,
     /// The last synced upstream SHA, which should be present
     /// if a pull was already performed at least once.
     pub last_upstream_sha: Option<String>,
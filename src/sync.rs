@@ -1,34 +1,89 @@
 use crate::SyncContext;
-use crate::config::{JoshConfig, PostPullOperation};
-use crate::josh::{JoshFilter, JoshProxy, try_install_josh_filter};
+use crate::changelog::Changelog;
+use crate::command::{CommandRunner, SystemRunner};
+use crate::config::{JoshConfig, PostPullOperation, SubtreeConfig};
+use crate::error::{Error, Result};
+use crate::git::{GitBackend, GixBackend};
+use crate::josh::{JoshFilter, JoshProxy, RunningJoshProxy};
+use crate::template::render;
 use crate::utils::{ensure_clean_git_state, prompt};
-use crate::utils::{get_current_head_sha, run_command_at};
-use crate::utils::{run_command, stream_command};
-use anyhow::{Context, Error};
+use crate::utils::{get_current_head_sha, is_full_sha};
+use anyhow::Context;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-pub const DEFAULT_UPSTREAM_REPO: &str = "rust-lang/rust";
+/// The result of a successful pull of a subtree.
+#[derive(serde::Serialize)]
+pub struct PullResult {
+    pub subtree: String,
+    /// The upstream commit that was pulled.
+    pub upstream_sha: String,
+    /// The upstream commit filtered by Josh, which was merged.
+    pub filtered_sha: String,
+    /// The created merge commit, if anything was merged.
+    pub merge_commit: Option<String>,
+    /// Commits created by the post-pull operations.
+    pub post_pull_commits: Vec<String>,
+    #[serde(skip)]
+    pub merge_commit_message: String,
+}
 
-pub enum RustcPullError {
-    /// No changes are available to be pulled.
-    NothingToPull,
-    /// A rustc-pull has failed, probably a git operation error has occurred.
-    PullFailed(anyhow::Error),
+/// What a pull would do, determined without modifying the working tree or the current branch.
+#[derive(serde::Serialize)]
+pub struct PullPreview {
+    pub subtree: String,
+    /// The upstream commit that would be pulled.
+    pub upstream_sha: String,
+    /// The upstream commit filtered by Josh, which would be merged.
+    pub incoming_ref: String,
+    /// Commits that would be merged, as `<short-sha> <subject>`.
+    pub commits: Vec<String>,
+    /// `git diff --stat` of the changes that would be merged.
+    pub diffstat: String,
+    /// Files that would have merge conflicts.
+    pub conflicts: Vec<String>,
 }
 
-impl From<anyhow::Error> for RustcPullError {
-    fn from(error: Error) -> Self {
-        Self::PullFailed(error)
-    }
+/// What a push would send to upstream, determined without publishing anything.
+#[derive(serde::Serialize)]
+pub struct PushPreview {
+    pub subtree: String,
+    /// The upstream commit that the pushed branch would be based on.
+    pub base_upstream_sha: String,
+    /// The base upstream commit filtered by Josh.
+    pub filtered_base: String,
+    /// Local commits that would be pushed, as `<short-sha> <subject>`.
+    pub commits: Vec<String>,
+    /// Path of the subtree in the upstream repository, if it can be determined.
+    pub upstream_path: Option<String>,
+    /// `git diff --stat` of the local changes that would be pushed. The paths are relative to the
+    /// subtree repository, not to `upstream_path`.
+    pub diffstat: String,
+    /// Whether the target branch already exists in the fork.
+    pub branch_exists: bool,
 }
 
-pub struct PullResult {
-    pub merge_commit_message: String,
+/// How far a subtree has diverged from upstream since the last sync.
+#[derive(serde::Serialize)]
+pub struct SyncStatus {
+    pub subtree: String,
+    /// The upstream SHA of the last pull.
+    pub last_upstream_sha: String,
+    /// The latest commit of the upstream branch.
+    pub upstream_sha: String,
+    /// Number of upstream commits touching the subtree that were not pulled yet.
+    pub upstream_commits: u64,
+    /// Number of local commits that were not pushed to upstream yet.
+    pub local_commits: u64,
+    /// Number of days since the last pull was merged.
+    pub days_since_last_sync: Option<u64>,
 }
 
 pub struct GitSync {
     context: SyncContext,
     proxy: JoshProxy,
+    runner: Arc<dyn CommandRunner>,
+    git: Arc<dyn GitBackend>,
     verbose: bool,
 }
 
@@ -37,71 +92,69 @@ impl GitSync {
         Self {
             context,
             proxy,
+            runner: Arc::new(SystemRunner::new(verbose)),
+            git: Arc::new(GixBackend::current_dir()),
             verbose,
         }
     }
 
+    /// Uses `runner` to execute git and `josh-filter`.
+    pub fn with_runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    /// Uses `git` to query the state of the local repository.
+    pub fn with_backend(mut self, git: Arc<dyn GitBackend>) -> Self {
+        self.git = git;
+        self
+    }
+
     pub fn rustc_pull(
         &self,
-        upstream_repo: String,
         upstream_commit: Option<String>,
         allow_noop: bool,
-    ) -> Result<PullResult, RustcPullError> {
+    ) -> Result<PullResult> {
+        let upstream_repo = &self.context.config.upstream_repo;
+        let forge = &self.context.config.forge;
         // The upstream commit that we want to pull
-        let upstream_sha = if let Some(sha) = upstream_commit {
-            sha
-        } else {
-            let out = run_command(
-                [
-                    "git",
-                    "ls-remote",
-                    &format!("https://github.com/{upstream_repo}"),
-                    "HEAD",
-                ],
-                self.verbose,
-            )
-            .context("cannot fetch upstream commit")?;
-            out.split_whitespace()
-                .next()
-                .unwrap_or_else(|| panic!("Could not obtain Rust repo HEAD from remote: '{out}'"))
-                .to_owned()
-        };
-
-        ensure_clean_git_state(self.verbose)?;
+        let upstream_sha = self.upstream_sha(upstream_commit)?;
 
-        // Make sure josh is running.
-        let josh = self
-            .proxy
-            .start(&self.context.config)
-            .context("cannot start josh-proxy")?;
-        let josh_url = josh.git_url(
-            &upstream_repo,
-            Some(&upstream_sha),
-            &self.context.config.construct_josh_filter(),
-        );
+        ensure_clean_git_state(self.git.as_ref())?;
+        if PullState::load(self.runner.as_ref())?.is_some() {
+            return Err(Error::PullInProgress);
+        }
 
-        let orig_head = get_current_head_sha(self.verbose)?;
-        println!(
+        let orig_head = get_current_head_sha(self.git.as_ref())?;
+        eprintln!(
             "previous upstream base: {}",
             self.context
                 .last_upstream_sha
                 .as_deref()
                 .unwrap_or("<none>"),
         );
-        println!("new upstream base: {upstream_sha}");
-        println!("original local HEAD: {orig_head}");
+        eprintln!("new upstream base: {upstream_sha}");
+        eprintln!("original local HEAD: {orig_head}");
 
         // If the upstream SHA hasn't changed from the latest sync, there is nothing to pull
         // We distinguish this situation for tools that might not want to consider this to
         // be an error.
-        if let Some(previous_base_commit) = self.context.last_upstream_sha.as_ref() {
-            if *previous_base_commit == upstream_sha {
-                return Err(RustcPullError::NothingToPull);
-            }
+        if let Some(previous_base_commit) = self.context.last_upstream_sha.as_ref()
+            && *previous_base_commit == upstream_sha
+        {
+            return Err(Error::NothingToPull);
         }
 
+        // Make sure josh is running.
+        let josh = self.start_josh()?;
+        let josh_url = josh.git_url(
+            upstream_repo,
+            Some(&upstream_sha),
+            &self.context.subtree.construct_josh_filter()?,
+        );
+
         // Create a checkpoint to which we reset if something unusual happens
-        let mut git_reset = GitResetOnDrop::new(orig_head, self.verbose);
+        let mut git_reset = GitResetOnDrop::new(orig_head.clone(), self.runner.as_ref());
 
         // Update the last upstream SHA file. As a separate commit, since making it part of
         // the merge has confused the heck out of josh in the past.
@@ -110,7 +163,7 @@ impl GitSync {
         // the right rust-version file while resolving them.
         std::fs::write(
             &self.context.last_upstream_sha_path,
-            &format!("{upstream_sha}\n"),
+            format!("{upstream_sha}\n"),
         )
         .with_context(|| {
             anyhow::anyhow!(
@@ -119,11 +172,16 @@ impl GitSync {
             )
         })?;
 
-        let prep_message = format!(
-            r#"Prepare for merging from {upstream_repo}
-
-This updates the rust-version file to {upstream_sha}."#,
-        );
+        let messages = &self.context.config.messages;
+        let previous_sha = self.context.last_upstream_sha.clone().unwrap_or_default();
+        let prep_message = render(
+            &messages.prep_commit,
+            &[
+                ("upstream_repo", upstream_repo),
+                ("upstream_sha", &upstream_sha),
+                ("previous_sha", &previous_sha),
+            ],
+        )?;
 
         let rust_version_path = self
             .context
@@ -132,284 +190,1065 @@ This updates the rust-version file to {upstream_sha}."#,
             .to_string();
         // Add the file to git index, in case this is the first time we perform the sync
         // Otherwise `git commit <file>` below wouldn't work.
-        run_command(&["git", "add", &rust_version_path], self.verbose)?;
-        run_command(
-            &[
+        self.runner.run(&["git", "add", &rust_version_path])?;
+        self.runner
+            .run(&[
                 "git",
                 "commit",
                 &rust_version_path,
                 "--no-verify",
                 "-m",
                 &prep_message,
-            ],
-            self.verbose,
-        )
-        .context("cannot create preparation commit")?;
+            ])
+            .context("cannot create preparation commit")?;
 
         // Fetch given rustc commit.
-        run_command(&["git", "fetch", &josh_url], self.verbose)
-            .context("cannot fetch git state through Josh")?;
+        self.runner
+            .run(&["git", "fetch", &josh_url])
+            .with_context(|| format!("cannot fetch git state through Josh\n{}", josh.log_tail()))?;
 
         // This should not add any new root commits. So count those before and after merging.
-        let num_roots = || -> anyhow::Result<u32> {
-            Ok(run_command(
-                &["git", "rev-list", "HEAD", "--max-parents=0", "--count"],
-                self.verbose,
-            )
-            .context("failed to determine the number of root commits")?
-            .parse::<u32>()?)
-        };
-        let num_roots_before = num_roots()?;
+        let num_roots_before = count_root_commits(self.git.as_ref())?;
 
-        let sha_pre_merge = get_current_head_sha(self.verbose)?;
+        let sha_pre_merge = get_current_head_sha(self.git.as_ref())?;
 
         // The filtered SHA of upstream
-        let incoming_ref = run_command(["git", "rev-parse", "FETCH_HEAD"], self.verbose)?;
-        println!("incoming ref: {incoming_ref}");
-
-        let merge_message = format!(
-            r#"Merge ref '{upstream_head_short}' from {upstream_repo}
+        let incoming_ref = self.runner.run(&["git", "rev-parse", "FETCH_HEAD"])?;
+        eprintln!("incoming ref: {incoming_ref}");
 
-Pull recent changes from https://github.com/{upstream_repo} via Josh.
+        let changelog = Changelog::load(&format!("HEAD..{incoming_ref}"), self.runner.as_ref())
+            .context("cannot determine the upstream changes")?;
 
-Upstream ref: {upstream_repo}@{upstream_sha}
-Filtered ref: {sub_org}/{sub_repo}@{incoming_ref}
-Upstream diff: https://github.com/{DEFAULT_UPSTREAM_REPO}/compare/{prev_upstream_sha}...{upstream_sha}
+        let upstream_diff_url = forge
+            .compare_url(
+                upstream_repo,
+                self.context
+                    .last_upstream_sha
+                    .as_deref()
+                    .unwrap_or(&upstream_sha),
+                &upstream_sha,
+            )
+            .unwrap_or_default();
+        let merge_message = render(
+            &messages.merge_commit,
+            &[
+                ("upstream_repo", upstream_repo),
+                ("upstream_url", &forge.repo_url(upstream_repo)),
+                ("upstream_sha", &upstream_sha),
+                ("upstream_sha_short", &upstream_sha[..12]),
+                ("previous_sha", &previous_sha),
+                ("upstream_diff_url", &upstream_diff_url),
+                ("filtered_sha", &incoming_ref),
+                ("repo", &self.context.config.full_repo_name()),
+                ("changelog", &changelog.to_markdown(upstream_repo)),
+            ],
+        )?;
 
-This merge was created using https://github.com/rust-lang/josh-sync.
-"#,
-            upstream_head_short = &upstream_sha[..12],
-            sub_org = self.context.config.org,
-            sub_repo = self.context.config.repo,
-            prev_upstream_sha = self
-                .context
-                .last_upstream_sha
-                .as_deref()
-                .unwrap_or(&upstream_sha)
-        );
+        let state = PullState {
+            subtree: self.context.subtree.name.clone(),
+            upstream_sha,
+            incoming_ref,
+            orig_head,
+            sha_pre_merge,
+            num_roots_before,
+            merge_message,
+            allow_noop,
+        };
 
         // Merge the fetched commit.
         // It is useful to print stdout/stderr here, because it shows the git diff summary
-        if let Err(error) = stream_command(
-            &[
+        if let Err(error) = self
+            .runner
+            .stream(&[
                 "git",
                 "merge",
                 "FETCH_HEAD",
                 "--no-verify",
                 "--no-ff",
                 "-m",
-                &merge_message,
-            ],
-            self.verbose,
-        )
-        .context("FAILED to merge new commits, something went wrong")
+                &state.merge_message,
+            ])
+            .context("FAILED to merge new commits, something went wrong")
         {
+            // Remember the pull, so that it can be finished by `pull --continue`
+            state.save(self.runner.as_ref())?;
             eprintln!(
                 r"The merge was unsuccessful (maybe there was a conflict?).
 NOT rolling back the branch state, so you can examine it manually.
-After you fix the conflicts, `git add` the changes and run `git merge --continue`."
+After you fix the conflicts, `git add` the changes and run `rustc-josh-sync pull --continue`.
+To roll back the pull instead, run `rustc-josh-sync pull --abort`."
             );
             git_reset.disarm();
-            return Err(RustcPullError::PullFailed(error));
+            return Err(Error::MergeConflict(error));
         }
 
-        // Now detect if something has actually been pulled
-        let current_sha = get_current_head_sha(self.verbose)?;
+        let result = finish_pull(
+            &self.context,
+            &state,
+            git_reset,
+            self.runner.as_ref(),
+            self.git.as_ref(),
+        );
+        josh.finish(matches!(result, Ok(_) | Err(Error::NothingToPull)));
+        result
+    }
 
-        // This is the easy case, no merge was performed, so we bail, unless `allow_noop` is true
-        if current_sha == sha_pre_merge && !allow_noop {
-            eprintln!("No merge was performed, no changes to pull were found. Rolling back.");
-            return Err(RustcPullError::NothingToPull);
-        }
+    /// Determines what `rustc_pull` would merge, without modifying the working tree or the
+    /// current branch (apart from `FETCH_HEAD`).
+    pub fn rustc_pull_preview(&self, upstream_commit: Option<String>) -> Result<PullPreview> {
+        let config = &self.context.config;
+        let upstream_sha = self.upstream_sha(upstream_commit)?;
+
+        let josh = self.start_josh()?;
+        let josh_url = josh.git_url(
+            &config.upstream_repo,
+            Some(&upstream_sha),
+            &self.context.subtree.construct_josh_filter()?,
+        );
+        self.runner
+            .run(&["git", "fetch", &josh_url])
+            .with_context(|| format!("cannot fetch git state through Josh\n{}", josh.log_tail()))?;
+        let incoming_ref = self.runner.run(&["git", "rev-parse", "FETCH_HEAD"])?;
+
+        let commits = self
+            .runner
+            .run(&[
+                "git",
+                "log",
+                "--format=%h %s",
+                &format!("HEAD..{incoming_ref}"),
+            ])?
+            .lines()
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>();
+        let diffstat = if commits.is_empty() {
+            String::new()
+        } else {
+            self.runner
+                .run(&["git", "diff", "--stat", &format!("HEAD...{incoming_ref}")])?
+        };
+
+        // `git merge-tree` performs the merge in-memory, and exits with 1 if there are conflicts.
+        // With `--name-only`, it prints the resulting tree followed by the conflicted files.
+        let (exit_code, output) = self.runner.run_with_exit_code(&[
+            "git",
+            "merge-tree",
+            "--write-tree",
+            "--name-only",
+            "--no-messages",
+            "HEAD",
+            &incoming_ref,
+        ])?;
+        let conflicts = match exit_code {
+            Some(0) => vec![],
+            Some(1) => output
+                .lines()
+                .skip(1)
+                .filter(|line| !line.is_empty())
+                .map(|line| line.to_string())
+                .collect(),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "cannot determine if the merge would conflict (exit code {exit_code:?}), \
+                     git 2.38 or newer is required"
+                )
+                .into());
+            }
+        };
+
+        josh.finish(true);
+        Ok(PullPreview {
+            subtree: self.context.subtree.name.clone(),
+            upstream_sha,
+            incoming_ref,
+            commits,
+            diffstat,
+            conflicts,
+        })
+    }
 
-        // But it can be more tricky - we can have only empty merge/rollup merge commits from
-        // rustc, so a merge was created, but the in-tree diff can still be empty.
-        // In that case we also bail, unless `allow_noop` is true.
-        if self.has_empty_diff(&sha_pre_merge) && !allow_noop {
-            eprintln!("Only empty changes were pulled. Rolling back.");
-            return Err(RustcPullError::NothingToPull);
+    /// Determines how far the subtree has diverged from upstream, without modifying the local
+    /// repository (apart from `FETCH_HEAD`).
+    pub fn status(&self) -> Result<SyncStatus> {
+        let config = &self.context.config;
+        let last_upstream_sha = self
+            .context
+            .last_upstream_sha
+            .clone()
+            .ok_or(Error::NoPreviousPull)?;
+        let upstream_sha = resolve_upstream_sha(config, self.runner.as_ref())?;
+
+        let josh = self.start_josh()?;
+        let filter = self.context.subtree.construct_josh_filter()?;
+        let fetch_filtered = |sha: &str| -> anyhow::Result<String> {
+            let josh_url = josh.git_url(&config.upstream_repo, Some(sha), &filter);
+            self.runner
+                .run(&["git", "fetch", &josh_url])
+                .with_context(|| {
+                    format!("cannot fetch git state through Josh\n{}", josh.log_tail())
+                })?;
+            self.runner.run(&["git", "rev-parse", "FETCH_HEAD"])
+        };
+        let count = |range: &str, extra_args: &[&str]| -> anyhow::Result<u64> {
+            let mut args = vec!["git", "rev-list", "--count"];
+            args.extend(extra_args);
+            args.push(range);
+            Ok(self.runner.run(&args)?.parse()?)
+        };
+
+        let filtered_upstream = fetch_filtered(&upstream_sha)?;
+        let upstream_commits = if upstream_sha == last_upstream_sha {
+            0
+        } else {
+            let filtered_last = fetch_filtered(&last_upstream_sha)?;
+            count(&format!("{filtered_last}..{filtered_upstream}"), &[])?
+        };
+        // Merges are not counted, as they either come from pulls or from PRs in the subtree
+        // repository, whose commits are counted.
+        let local_commits = count(&format!("{filtered_upstream}..HEAD"), &["--no-merges"])?;
+
+        // The last sync happened when the rust-version file was last modified.
+        let last_sync_time = self.runner.run(&[
+            "git",
+            "log",
+            "-1",
+            "--format=%ct",
+            "--",
+            &self.context.last_upstream_sha_path.to_string_lossy(),
+        ])?;
+        let days_since_last_sync = last_sync_time.parse::<u64>().ok().map(|time| {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default();
+            now.saturating_sub(time) / (24 * 60 * 60)
+        });
+
+        josh.finish(true);
+        Ok(SyncStatus {
+            subtree: self.context.subtree.name.clone(),
+            last_upstream_sha,
+            upstream_sha,
+            upstream_commits,
+            local_commits,
+            days_since_last_sync,
+        })
+    }
+
+    /// Pushes the subtree to its branch in the fork, running the remaining stages of its
+    /// entry in `state`. The state is saved after each finished stage, so that a failed push
+    /// can be resumed.
+    pub fn rustc_push(&self, state: &mut PushState) -> Result<()> {
+        ensure_clean_git_state(self.git.as_ref())?;
+
+        let config = &self.context.config;
+        let name = &self.context.subtree.name;
+        let Some(index) = state.subtrees.iter().position(|push| push.name == *name) else {
+            return Err(
+                anyhow::anyhow!("subtree `{name}` is not part of the push in progress").into(),
+            );
+        };
+        let push = state.subtrees[index].clone();
+        if get_current_head_sha(self.git.as_ref())? != push.head {
+            return Err(anyhow::anyhow!(
+                "HEAD has changed since the push was started from {}. \
+                Run `rustc-josh-sync push --cleanup` and push again.",
+                push.head
+            )
+            .into());
         }
+        let branch = &push.branch;
+        let base_upstream_sha = &push.base_upstream_sha;
 
-        println!("Pull finished! Current HEAD is {current_sha}");
+        // Make sure josh is running.
+        let josh = self.start_josh()?;
+        let fork = format!("{}/{}", state.username, config.upstream_repo_name());
+        let josh_url = josh.git_url(&fork, None, &self.context.subtree.construct_josh_filter()?);
+        let user_upstream_url = config.forge.repo_url(&fork);
 
-        if !self.context.config.post_pull.is_empty() {
-            println!("Running post-pull operation(s)");
+        let rustc_git = prepare_rustc_checkout(config, self.runner.as_ref())
+            .context("cannot prepare rustc checkout")?;
 
-            for op in &self.context.config.post_pull {
-                self.run_post_pull_op(&op)?;
+        let mut stage = push.stage;
+        loop {
+            match stage {
+                PushStage::FetchBase => {
+                    let previous_tip = self.fetch_base(&rustc_git, &user_upstream_url, &push)?;
+                    if previous_tip.is_some() {
+                        state.subtrees[index].rustc_git = Some(rustc_git.clone());
+                    }
+                    state.subtrees[index].previous_tip = previous_tip;
+                }
+                PushStage::PushBase => {
+                    // And push it to the user's fork's branch.
+                    // When updating a branch, it is reset to the base, unless somebody else has
+                    // modified it in the meantime. The new changes are then pushed on top of it,
+                    // exactly as for a new branch.
+                    let lease = state.subtrees[index]
+                        .previous_tip
+                        .as_ref()
+                        .map(|tip| format!("--force-with-lease=refs/heads/{branch}:{tip}"));
+                    let mut args = vec!["git", "push"];
+                    args.extend(lease.as_deref());
+                    let refspec = format!("{base_upstream_sha}:refs/heads/{branch}");
+                    args.extend([user_upstream_url.as_str(), refspec.as_str()]);
+                    self.runner
+                        .run_at(&args, Some(&rustc_git))
+                        .context("cannot push to your fork")?;
+                    eprintln!();
+                }
+                PushStage::PushThroughJosh => {
+                    // Do the actual push from the subtree git repo
+                    eprintln!("Pushing changes...");
+                    self.runner
+                        .run(&["git", "push", &josh_url, &format!("HEAD:{branch}")])
+                        .with_context(|| {
+                            format!("cannot push through Josh\n{}", josh.log_tail())
+                        })?;
+                    eprintln!();
+                }
+                PushStage::RoundtripCheck => {
+                    // Do a round-trip check to make sure the push worked as expected.
+                    self.runner
+                        .run(&["git", "fetch", &josh_url, branch])
+                        .with_context(|| {
+                            format!(
+                                "cannot fetch pushed branch through Josh\n{}",
+                                josh.log_tail()
+                            )
+                        })?;
+                    self.roundtrip_check(&self.context.subtree)?;
+                }
+                PushStage::Done => break,
             }
+            stage = stage.next();
+            state.subtrees[index].stage = stage;
+            state.save(self.runner.as_ref())?;
         }
 
-        git_reset.disarm();
+        josh.finish(true);
+        Ok(())
+    }
+
+    /// Estimates what `rustc_push` would push from the local history, without pushing anything.
+    /// Josh only filters the upstream base here, so the round-trip is not verified. This fails
+    /// only if HEAD is not based on the filtered base, as the push would not round-trip then.
+    pub fn rustc_push_preview(&self, username: &str, branch: &str) -> Result<PushPreview> {
+        let config = &self.context.config;
+        let base_upstream_sha = self
+            .context
+            .last_upstream_sha
+            .clone()
+            .ok_or(Error::NoPreviousPull)?;
+
+        let josh = self.start_josh()?;
+        let josh_url = josh.git_url(
+            &config.upstream_repo,
+            Some(&base_upstream_sha),
+            &self.context.subtree.construct_josh_filter()?,
+        );
+        self.runner
+            .run(&["git", "fetch", &josh_url])
+            .with_context(|| format!("cannot fetch git state through Josh\n{}", josh.log_tail()))?;
+        let filtered_base = self.runner.run(&["git", "rev-parse", "FETCH_HEAD"])?;
 
-        // Check that the number of roots did not change.
-        if num_roots()? != num_roots_before {
+        // Josh can only map the pushed history back onto upstream if it is built on top of
+        // the filtered base. Otherwise, the round-trip check after the push would fail.
+        let head = self.expected_roundtrip_head(&self.context.subtree)?;
+        if self
+            .runner
+            .run(&["git", "merge-base", "--is-ancestor", &filtered_base, &head])
+            .is_err()
+        {
             return Err(anyhow::anyhow!(
-                "Josh created a new root commit. This is probably not the history you want."
+                "Josh would create a non-roundtrip push!\n\
+                {head} does not contain the filtered upstream base {filtered_base}. \
+                Make sure that the latest pull has been merged."
             )
             .into());
         }
 
-        Ok(PullResult {
-            merge_commit_message: merge_message,
-        })
-    }
+        let commits = self
+            .runner
+            .run(&[
+                "git",
+                "log",
+                "--format=%h %s",
+                &format!("{filtered_base}..{head}"),
+            ])?
+            .lines()
+            .map(|line| line.to_string())
+            .collect();
+        let diffstat = self
+            .runner
+            .run(&["git", "diff", "--stat", &filtered_base, &head])?;
 
-    pub fn rustc_push(&self, username: &str, branch: &str) -> anyhow::Result<()> {
-        ensure_clean_git_state(self.verbose)?;
+        let fork = format!("{username}/{}", config.upstream_repo_name());
+        let branch_exists = !self
+            .runner
+            .run(&[
+                "git",
+                "ls-remote",
+                &config.forge.repo_url(&fork),
+                &format!("refs/heads/{branch}"),
+            ])
+            .unwrap_or_default()
+            .is_empty();
 
-        let base_upstream_sha = self.context.last_upstream_sha.clone().unwrap_or_default();
+        josh.finish(true);
+        Ok(PushPreview {
+            subtree: self.context.subtree.name.clone(),
+            base_upstream_sha,
+            filtered_base,
+            commits,
+            upstream_path: self.context.subtree.upstream_path(),
+            diffstat,
+            branch_exists,
+        })
+    }
 
-        // Make sure josh is running.
-        let josh = self
-            .proxy
-            .start(&self.context.config)
-            .context("cannot start josh-proxy")?;
-        let josh_url = josh.git_url(
-            &format!("{username}/rust"),
-            None,
-            &self.context.config.construct_josh_filter(),
+    /// Checks that the pushed branch, which was fetched back through Josh into `FETCH_HEAD`,
+    /// maps to the local history.
+    fn roundtrip_check(&self, subtree: &SubtreeConfig) -> Result<()> {
+        let head = self.expected_roundtrip_head(subtree)?;
+        let fetch_head = self.runner.run(&["git", "rev-parse", "FETCH_HEAD"])?;
+        if head != fetch_head {
+            return Err(Error::NonRoundtripPush {
+                expected: head,
+                actual: fetch_head,
+            });
+        }
+        eprintln!(
+            "Confirmed that the push round-trips back to {} properly. Please create a rustc PR.",
+            self.context.config.repo
         );
-        let user_upstream_url = format!("https://github.com/{username}/rust");
+        Ok(())
+    }
 
-        let rustc_git =
-            prepare_rustc_checkout(self.verbose).context("cannot prepare rustc checkout")?;
+    /// Fetches the base upstream commit of `push` into the rustc checkout and checks the branch in
+    /// the fork, returning the tip of the branch that is updated (which has to be based on that
+    /// commit) or `None` for a new branch.
+    fn fetch_base(
+        &self,
+        rustc_git: &Path,
+        user_upstream_url: &str,
+        push: &SubtreePush,
+    ) -> Result<Option<String>> {
+        let config = &self.context.config;
+        let branch = &push.branch;
+        let base_upstream_sha = &push.base_upstream_sha;
 
         // Prepare the branch. Pushing works much better if we use as base exactly
         // the commit that we pulled from last time, so we use the `rust-version`
         // file to find out which commit that would be.
-        println!("Preparing {user_upstream_url} (base: {base_upstream_sha})...");
+        eprintln!("Preparing {user_upstream_url} (base: {base_upstream_sha})...");
 
-        // Check if the remote branch doesn't already exist
-        if run_command_at(
-            &["git", "fetch", &user_upstream_url, branch],
-            &rustc_git,
-            self.verbose,
-        )
-        .is_ok()
-        {
-            return Err(anyhow::anyhow!(
-                "The branch '{branch}' seems to already exist in '{user_upstream_url}'. Please delete it and try again."
-            ));
+        // Download the base upstream SHA
+        self.runner
+            .run_at(
+                &[
+                    "git",
+                    "fetch",
+                    &config.forge.repo_url(&config.upstream_repo),
+                    base_upstream_sha,
+                ],
+                Some(rustc_git),
+            )
+            .context("cannot download latest upstream SHA")?;
+
+        let branch_exists = self
+            .runner
+            .run_at(
+                &["git", "fetch", user_upstream_url, branch],
+                Some(rustc_git),
+            )
+            .is_ok();
+        if !push.update {
+            if branch_exists {
+                return Err(Error::BranchExists {
+                    branch: branch.clone(),
+                    fork: user_upstream_url.to_string(),
+                });
+            }
+            return Ok(None);
+        }
+        if !branch_exists {
+            return Err(Error::BranchMissing {
+                branch: branch.clone(),
+                fork: user_upstream_url.to_string(),
+            });
         }
 
-        // Download the base upstream SHA
-        run_command_at(
-            &[
-                "git",
-                "fetch",
-                &format!("https://github.com/{DEFAULT_UPSTREAM_REPO}"),
-                &base_upstream_sha,
-            ],
-            &rustc_git,
-            self.verbose,
-        )
-        .context("cannot download latest upstream SHA")?;
+        // The base has been fetched above, so that it can be compared with the branch
+        let previous_tip = self
+            .runner
+            .run_at(&["git", "rev-parse", "FETCH_HEAD"], Some(rustc_git))?;
+        let merge_base = self
+            .runner
+            .run_at(
+                &["git", "merge-base", &previous_tip, base_upstream_sha],
+                Some(rustc_git),
+            )
+            .context("cannot determine the base of the existing branch")?;
+        if merge_base != *base_upstream_sha {
+            return Err(Error::BranchBaseMismatch {
+                branch: branch.clone(),
+                base: base_upstream_sha.clone(),
+            });
+        }
+        Ok(Some(previous_tip))
+    }
+
+    /// Returns the commit that a pushed branch should map to when fetched back through Josh.
+    fn expected_roundtrip_head(&self, subtree: &SubtreeConfig) -> Result<String> {
+        if let Some(subtree_filter) = &subtree.subtree_filter {
+            self.josh_filter()?.run(&[subtree_filter, "HEAD"], None)?;
+            Ok(self
+                .runner
+                .run(&["git", "rev-parse", "FILTERED_HEAD"])
+                .context("failed to get FILTERED_HEAD")?)
+        } else {
+            Ok(get_current_head_sha(self.git.as_ref())?)
+        }
+    }
+
+    // This is called only when the `subtree-filter` is set.
+    fn josh_filter(&self) -> Result<JoshFilter> {
+        JoshFilter::find_or_install(self.verbose)
+            .context("Could not install josh-filter")
+            .map(|filter| filter.with_runner(self.runner.clone()))
+            .map_err(Error::JoshUnavailable)
+    }
 
-        // And push it to the user's fork's branch
-        run_command_at(
+    /// Validates the `upstream_commit` passed by the user, or resolves the latest upstream commit.
+    fn upstream_sha(&self, upstream_commit: Option<String>) -> Result<String> {
+        match upstream_commit {
+            Some(sha) if is_full_sha(&sha) => Ok(sha),
+            Some(sha) => Err(Error::InvalidUpstreamCommit(sha)),
+            None => Ok(resolve_upstream_sha(
+                &self.context.config,
+                self.runner.as_ref(),
+            )?),
+        }
+    }
+
+    /// Starts josh-proxy with the config of the synchronized repository.
+    fn start_josh(&self) -> Result<RunningJoshProxy> {
+        self.proxy
+            .start(&self.context.config)
+            .context("cannot start josh-proxy")
+            .map_err(Error::JoshUnavailable)
+    }
+}
+
+/// Stages of a push, in the order in which they are performed.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PushStage {
+    /// Download the upstream base commit into the rustc checkout.
+    FetchBase,
+    /// Create the branch in the fork, pointing to the upstream base commit.
+    PushBase,
+    /// Push the subtree changes to the branch through Josh.
+    PushThroughJosh,
+    /// Check that the pushed branch maps back to the local history.
+    RoundtripCheck,
+    Done,
+}
+
+impl PushStage {
+    fn next(self) -> Self {
+        match self {
+            PushStage::FetchBase => PushStage::PushBase,
+            PushStage::PushBase => PushStage::PushThroughJosh,
+            PushStage::PushThroughJosh => PushStage::RoundtripCheck,
+            PushStage::RoundtripCheck | PushStage::Done => PushStage::Done,
+        }
+    }
+}
+
+/// Progress of a push of one or more subtrees, stored in the `.git` directory.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct PushState {
+    pub username: String,
+    #[serde(rename = "subtree")]
+    pub subtrees: Vec<SubtreePush>,
+}
+
+/// Progress of the push of a single subtree.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct SubtreePush {
+    pub name: String,
+    /// Branch of the fork that the subtree is pushed to.
+    pub branch: String,
+    /// The upstream commit that the branch is based on.
+    pub base_upstream_sha: String,
+    /// Local HEAD that is being pushed.
+    pub head: String,
+    /// The next stage to perform.
+    pub stage: PushStage,
+    /// Whether an existing branch is updated, instead of creating a new one.
+    #[serde(default)]
+    pub update: bool,
+    /// The commit that the updated branch pointed to before the push.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_tip: Option<String>,
+    /// The rustc checkout in which the previous tip was fetched, used to restore it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rustc_git: Option<PathBuf>,
+    /// Whether a pull request should be opened once the branch is pushed.
+    #[serde(default)]
+    pub open_pr: bool,
+}
+
+impl PushState {
+    fn path(runner: &dyn CommandRunner) -> anyhow::Result<PathBuf> {
+        let path = runner
+            .run(&["git", "rev-parse", "--git-path", "josh-sync-push.toml"])
+            .context("cannot find the git directory")?;
+        Ok(PathBuf::from(path))
+    }
+
+    /// Starts a new push of the given subtrees, failing if another push is in progress.
+    pub fn start(
+        username: &str,
+        subtrees: Vec<SubtreePush>,
+        runner: &dyn CommandRunner,
+    ) -> Result<Self> {
+        if Self::load(runner)?.is_some() {
+            return Err(Error::PushInProgress);
+        }
+        let state = Self {
+            username: username.to_string(),
+            subtrees,
+        };
+        state.save(runner)?;
+        Ok(state)
+    }
+
+    pub fn load(runner: &dyn CommandRunner) -> anyhow::Result<Option<Self>> {
+        let path = Self::path(runner)?;
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let state = std::fs::read_to_string(&path)
+            .with_context(|| format!("cannot read {}", path.display()))?;
+        let state = toml::from_str(&state)
+            .with_context(|| format!("cannot parse push state from {}", path.display()))?;
+        Ok(Some(state))
+    }
+
+    fn save(&self, runner: &dyn CommandRunner) -> anyhow::Result<()> {
+        let path = Self::path(runner)?;
+        std::fs::write(&path, toml::to_string_pretty(self)?)
+            .with_context(|| format!("cannot write push state to {}", path.display()))
+    }
+
+    /// Removes the stored state once all subtrees were pushed.
+    pub fn finish(self, runner: &dyn CommandRunner) -> anyhow::Result<()> {
+        let path = Self::path(runner)?;
+        std::fs::remove_file(&path)
+            .with_context(|| format!("cannot remove push state {}", path.display()))
+    }
+}
+
+/// Deletes the branches of a failed push from the fork and forgets the push. Branches updated
+/// with `--update` are restored to their previous tip instead.
+/// Branches of subtrees that were pushed successfully are kept.
+pub fn cleanup_push(config: &JoshConfig, runner: &dyn CommandRunner) -> Result<()> {
+    let state = PushState::load(runner)?.ok_or(Error::NoPushInProgress)?;
+    let fork = format!("{}/{}", state.username, config.upstream_repo_name());
+    let fork_url = config.forge.repo_url(&fork);
+    for push in &state.subtrees {
+        // The branch only exists in the fork (or was reset to the base) once the base was pushed
+        if !matches!(
+            push.stage,
+            PushStage::PushThroughJosh | PushStage::RoundtripCheck
+        ) {
+            continue;
+        }
+        if let Some(previous_tip) = &push.previous_tip {
+            restore_updated_branch(config, push, previous_tip, &fork_url, runner)?;
+        } else {
+            eprintln!("Deleting branch `{}` from {fork_url}", push.branch);
+            runner
+                .run(&["git", "push", &fork_url, "--delete", &push.branch])
+                .with_context(|| {
+                    format!("cannot delete branch `{}` from your fork", push.branch)
+                })?;
+        }
+    }
+    Ok(state.finish(runner)?)
+}
+
+/// Resets a branch that was updated by a failed push back to `previous_tip`, unless somebody
+/// else has modified it since the push.
+fn restore_updated_branch(
+    config: &JoshConfig,
+    push: &SubtreePush,
+    previous_tip: &str,
+    fork_url: &str,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    let branch = &push.branch;
+    // The previous tip is only available in the rustc checkout
+    let rustc_git = match &push.rustc_git {
+        Some(rustc_git) => rustc_git.clone(),
+        None => prepare_rustc_checkout(config, runner).context("cannot prepare rustc checkout")?,
+    };
+    let current_tip = if push.stage == PushStage::PushThroughJosh {
+        // The branch was reset to the base, but Josh has not pushed anything yet
+        push.base_upstream_sha.clone()
+    } else {
+        let out = runner
+            .run_at(
+                &[
+                    "git",
+                    "ls-remote",
+                    fork_url,
+                    &format!("refs/heads/{branch}"),
+                ],
+                Some(&rustc_git),
+            )
+            .with_context(|| format!("cannot find branch `{branch}` in your fork"))?;
+        out.split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    };
+    eprintln!("Restoring branch `{branch}` in {fork_url} to {previous_tip}");
+    runner
+        .run_at(
             &[
                 "git",
                 "push",
-                &user_upstream_url,
-                &format!("{base_upstream_sha}:refs/heads/{branch}"),
+                &format!("--force-with-lease=refs/heads/{branch}:{current_tip}"),
+                fork_url,
+                &format!("{previous_tip}:refs/heads/{branch}"),
             ],
-            &rustc_git,
-            self.verbose,
+            Some(&rustc_git),
         )
-        .context("cannot push to your fork")?;
-        println!();
-
-        // Do the actual push from the subtree git repo
-        println!("Pushing changes...");
-        run_command(
-            &["git", "push", &josh_url, &format!("HEAD:{branch}")],
-            self.verbose,
-        )?;
-        println!();
+        .with_context(|| format!("cannot restore branch `{branch}` in your fork"))?;
+    Ok(())
+}
 
-        // Do a round-trip check to make sure the push worked as expected.
-        self.roundtrip_check(&self.context.config, &josh_url, &branch)?;
+/// State of a pull that was interrupted by a failed merge, stored in the `.git` directory.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+struct PullState {
+    /// Name of the pulled subtree.
+    subtree: String,
+    /// The upstream commit that is pulled.
+    #[serde(default)]
+    upstream_sha: String,
+    /// The upstream commit filtered by Josh, which is merged.
+    #[serde(default)]
+    incoming_ref: String,
+    /// HEAD before the pull, to which the branch is reset if the pull is aborted.
+    orig_head: String,
+    /// HEAD after the preparation commit, before the merge.
+    sha_pre_merge: String,
+    num_roots_before: u32,
+    merge_message: String,
+    allow_noop: bool,
+}
 
-        Ok(())
+impl PullState {
+    fn path(runner: &dyn CommandRunner) -> anyhow::Result<PathBuf> {
+        let path = runner
+            .run(&["git", "rev-parse", "--git-path", "josh-sync-pull.toml"])
+            .context("cannot find the git directory")?;
+        Ok(PathBuf::from(path))
+    }
+
+    fn load(runner: &dyn CommandRunner) -> anyhow::Result<Option<Self>> {
+        let path = Self::path(runner)?;
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let state = std::fs::read_to_string(&path)
+            .with_context(|| format!("cannot read {}", path.display()))?;
+        let state = toml::from_str(&state)
+            .with_context(|| format!("cannot parse pull state from {}", path.display()))?;
+        Ok(Some(state))
     }
 
-    fn has_empty_diff(&self, baseline_sha: &str) -> bool {
-        // `git diff --exit-code` "succeeds" if the diff is empty.
-        run_command(&["git", "diff", "--exit-code", baseline_sha], self.verbose).is_ok()
+    fn save(&self, runner: &dyn CommandRunner) -> anyhow::Result<()> {
+        let path = Self::path(runner)?;
+        std::fs::write(&path, toml::to_string_pretty(self)?)
+            .with_context(|| format!("cannot write pull state to {}", path.display()))
     }
 
-    fn run_post_pull_op(&self, op: &PostPullOperation) -> anyhow::Result<()> {
-        let head = get_current_head_sha(self.verbose)?;
-        run_command(op.cmd.iter().map(|s| s.as_str()).collect::<Vec<_>>(), true)?;
-        if !self.has_empty_diff(&head) {
-            println!(
-                "`{}` changed something, committing with message `{}`",
-                op.cmd.join(" "),
-                op.commit_message
-            );
-            run_command(["git", "add", "-u"], self.verbose)?;
-            run_command(["git", "commit", "-m", &op.commit_message], self.verbose)?;
+    fn remove(runner: &dyn CommandRunner) -> anyhow::Result<()> {
+        let path = Self::path(runner)?;
+        std::fs::remove_file(&path)
+            .with_context(|| format!("cannot remove pull state {}", path.display()))
+    }
+}
+
+/// Finishes a pull that was interrupted by a failed merge, once the conflicts were resolved.
+/// `contexts` has to contain the context of the interrupted subtree.
+pub fn continue_pull(
+    contexts: &[SyncContext],
+    runner: &dyn CommandRunner,
+    git: &dyn GitBackend,
+) -> Result<PullResult> {
+    let state = load_pull_state(runner)?;
+    let context = contexts
+        .iter()
+        .find(|ctx| ctx.subtree.name == state.subtree)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "the pull in progress is for subtree `{}`, which was not selected",
+                state.subtree
+            )
+        })?;
+
+    if is_merge_in_progress(runner) {
+        let unmerged = runner.run(&["git", "diff", "--name-only", "--diff-filter=U"])?;
+        if !unmerged.is_empty() {
+            return Err(Error::UnresolvedConflicts(unmerged));
         }
+        runner
+            .run(&["git", "commit", "--no-verify", "-m", &state.merge_message])
+            .context("cannot commit the merge")?;
+    }
+    ensure_clean_git_state(git)?;
 
-        Ok(())
+    // From now on, behave like an uninterrupted pull, except that a failure keeps the
+    // committed merge, so that the conflict resolution is not lost.
+    let merge_commit = get_current_head_sha(git)?;
+    let git_reset = GitResetOnDrop::new(merge_commit, runner);
+    let result = finish_pull(context, &state, git_reset, runner, git);
+    match &result {
+        Ok(_) | Err(Error::NothingToPull | Error::NewRootCommit) => PullState::remove(runner)?,
+        // Keep the state, so that the pull can be continued once the problem is fixed
+        Err(_) => eprintln!("Fix the problem and run `rustc-josh-sync pull --continue` again"),
     }
+    result
+}
 
-    fn roundtrip_check(
-        &self,
-        config: &JoshConfig,
-        josh_url: &str,
-        branch: &str,
-    ) -> anyhow::Result<()> {
-        run_command_at(
-            &["git", "fetch", josh_url, branch],
-            &std::env::current_dir().unwrap(),
-            self.verbose,
-        )?;
-        let head = if let Some(subtree_filter) = &config.subtree_filter {
-            let josh_filter = get_josh_filter(self.verbose)?;
-            josh_filter.run(
-                &[subtree_filter, "HEAD"],
-                &std::env::current_dir().unwrap(),
-                self.verbose,
-            )?;
-            run_command(&["git", "rev-parse", "FILTERED_HEAD"], self.verbose)
-                .context("failed to get FILTERED_HEAD")?
-        } else {
-            get_current_head_sha(self.verbose)?
-        };
-        let fetch_head = run_command(&["git", "rev-parse", "FETCH_HEAD"], self.verbose)?;
-        if head != fetch_head {
-            return Err(anyhow::anyhow!(
-                "Josh created a non-roundtrip push! Do NOT merge this into rustc!\n\
-                Expected {head}, got {fetch_head}."
-            ));
+/// Rolls back a pull that was interrupted by a failed merge.
+pub fn abort_pull(runner: &dyn CommandRunner) -> Result<()> {
+    let state = load_pull_state(runner)?;
+    // This also clears the state of the failed merge
+    runner
+        .run(&["git", "reset", "--hard", &state.orig_head])
+        .with_context(|| format!("cannot reset current branch to {}", state.orig_head))?;
+    PullState::remove(runner)?;
+    eprintln!("Pull aborted, HEAD was reset to {}", state.orig_head);
+    Ok(())
+}
+
+fn load_pull_state(runner: &dyn CommandRunner) -> Result<PullState> {
+    PullState::load(runner)?.ok_or(Error::NoPullInProgress)
+}
+
+fn is_merge_in_progress(runner: &dyn CommandRunner) -> bool {
+    runner
+        .run(&["git", "rev-parse", "-q", "--verify", "MERGE_HEAD"])
+        .is_ok()
+}
+
+/// Checks the result of a successful merge and runs the post-pull operations.
+fn finish_pull(
+    context: &SyncContext,
+    state: &PullState,
+    mut git_reset: GitResetOnDrop<'_>,
+    runner: &dyn CommandRunner,
+    git: &dyn GitBackend,
+) -> Result<PullResult> {
+    // Now detect if something has actually been pulled
+    let current_sha = get_current_head_sha(git)?;
+
+    // This is the easy case, no merge was performed, so we bail, unless `allow_noop` is true
+    if current_sha == state.sha_pre_merge && !state.allow_noop {
+        eprintln!("No merge was performed, no changes to pull were found. Rolling back.");
+        git_reset.reset_to(state.orig_head.clone());
+        return Err(Error::NothingToPull);
+    }
+
+    // But it can be more tricky - we can have only empty merge/rollup merge commits from
+    // rustc, so a merge was created, but the in-tree diff can still be empty.
+    // In that case we also bail, unless `allow_noop` is true.
+    if !state.allow_noop && has_empty_diff(&state.sha_pre_merge, git)? {
+        eprintln!("Only empty changes were pulled. Rolling back.");
+        git_reset.reset_to(state.orig_head.clone());
+        return Err(Error::NothingToPull);
+    }
+
+    eprintln!("Pull finished! Current HEAD is {current_sha}");
+
+    let mut post_pull_commits = vec![];
+    if !context.subtree.post_pull.is_empty() {
+        eprintln!("Running post-pull operation(s)");
+
+        for op in &context.subtree.post_pull {
+            post_pull_commits.extend(run_post_pull_op(op, runner, git)?);
         }
-        println!(
-            "Confirmed that the push round-trips back to {} properly. Please create a rustc PR.",
-            self.context.config.repo
+    }
+
+    git_reset.disarm();
+
+    // Check that the number of roots did not change.
+    if count_root_commits(git)? != state.num_roots_before {
+        return Err(Error::NewRootCommit);
+    }
+
+    Ok(PullResult {
+        subtree: state.subtree.clone(),
+        upstream_sha: state.upstream_sha.clone(),
+        filtered_sha: state.incoming_ref.clone(),
+        merge_commit: (current_sha != state.sha_pre_merge).then_some(current_sha),
+        post_pull_commits,
+        merge_commit_message: state.merge_message.clone(),
+    })
+}
+
+fn count_root_commits(git: &dyn GitBackend) -> anyhow::Result<u32> {
+    git.count_root_commits()
+        .context("failed to determine the number of root commits")
+}
+
+fn has_empty_diff(baseline_sha: &str, git: &dyn GitBackend) -> anyhow::Result<bool> {
+    git.has_empty_diff(baseline_sha)
+        .with_context(|| format!("cannot determine the changes since {baseline_sha}"))
+}
+
+/// Runs a post-pull operation, and returns the commit that it has created, if any.
+fn run_post_pull_op(
+    op: &PostPullOperation,
+    runner: &dyn CommandRunner,
+    git: &dyn GitBackend,
+) -> anyhow::Result<Option<String>> {
+    let head = get_current_head_sha(git)?;
+    let cmd = op.cmd.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+    eprintln!("+ {}", op.cmd.join(" "));
+    runner.run(&cmd)?;
+    if !has_empty_diff(&head, git)? {
+        eprintln!(
+            "`{}` changed something, committing with message `{}`",
+            op.cmd.join(" "),
+            op.commit_message
         );
-        Ok(())
+        runner.run(&["git", "add", "-u"])?;
+        runner.run(&["git", "commit", "-m", &op.commit_message])?;
+        return Ok(Some(get_current_head_sha(git)?));
     }
+
+    Ok(None)
 }
 
-// This is called only when the `subtree-filter` is set.
-fn get_josh_filter(verbose: bool) -> anyhow::Result<JoshFilter> {
-    println!("Updating/installing josh-filter binary...");
-    match try_install_josh_filter(verbose) {
-        Some(filter) => Ok(filter),
-        None => Err(anyhow::anyhow!("Could not install josh-filter")),
+/// Returns the messages of the merge commits created by the latest pull, oldest first.
+///
+/// The pull is found by walking the first-parent history from `HEAD`, as long as it consists
+/// of pull merges (whose first parent only changes `rust-version` files), their preparation
+/// commits and commits of post-pull operations.
+pub fn latest_pull_merge_messages(
+    config: &JoshConfig,
+    default_rust_version_path: &Path,
+    runner: &dyn CommandRunner,
+) -> anyhow::Result<Vec<String>> {
+    let subtrees = config.subtrees();
+    let rust_version_paths: Vec<String> = subtrees
+        .iter()
+        .map(|subtree| {
+            let path = subtree
+                .rust_version
+                .as_deref()
+                .unwrap_or(default_rust_version_path);
+            let path = path.to_string_lossy();
+            path.strip_prefix("./").unwrap_or(&path).to_string()
+        })
+        .collect();
+    let post_pull_messages: Vec<&str> = subtrees
+        .iter()
+        .flat_map(|subtree| &subtree.post_pull)
+        .map(|op| op.commit_message.trim())
+        .collect();
+
+    let mut messages = vec![];
+    let mut commit = "HEAD".to_string();
+    loop {
+        let Ok(out) = runner.run(&["git", "log", "-1", "--format=%P%n%B", &commit]) else {
+            // We have walked past the root commit
+            break;
+        };
+        let (parents, message) = out.split_once('\n').unwrap_or((&out, ""));
+        let parents: Vec<&str> = parents.split_whitespace().collect();
+        let Some(first_parent) = parents.first() else {
+            break;
+        };
+        if post_pull_messages.contains(&message.trim()) {
+            commit = first_parent.to_string();
+            continue;
+        }
+        if parents.len() == 2 {
+            let changed = runner.run(&[
+                "git",
+                "diff-tree",
+                "--no-commit-id",
+                "--name-only",
+                "-r",
+                first_parent,
+            ])?;
+            if !changed.is_empty()
+                && changed
+                    .lines()
+                    .all(|path| rust_version_paths.iter().any(|p| p == path))
+            {
+                messages.push(message.trim().to_string());
+                // Continue before the preparation commit
+                commit = format!("{first_parent}^");
+                continue;
+            }
+        }
+        break;
     }
+    messages.reverse();
+    Ok(messages)
+}
+
+/// Returns the SHA of the latest commit of the upstream branch.
+pub fn resolve_upstream_sha(
+    config: &JoshConfig,
+    runner: &dyn CommandRunner,
+) -> anyhow::Result<String> {
+    let upstream_ref = config.upstream_ref();
+    let out = runner
+        .run(&[
+            "git",
+            "ls-remote",
+            &config.forge.repo_url(&config.upstream_repo),
+            &upstream_ref,
+        ])
+        .context("cannot fetch upstream commit")?;
+    out.split_whitespace()
+        .next()
+        .map(|sha| sha.to_owned())
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Could not obtain {upstream_ref} of {} from remote: '{out}'",
+                config.upstream_repo
+            )
+        })
 }
 
 /// Find a rustc repo we can do our push preparation in.
-fn prepare_rustc_checkout(verbose: bool) -> anyhow::Result<PathBuf> {
+fn prepare_rustc_checkout(
+    config: &JoshConfig,
+    runner: &dyn CommandRunner,
+) -> anyhow::Result<PathBuf> {
     if let Ok(rustc_git) = std::env::var("RUSTC_GIT") {
         let rustc_git = PathBuf::from(rustc_git);
-        assert!(
-            rustc_git.is_dir(),
-            "rustc checkout path must be a directory"
-        );
+        if !rustc_git.is_dir() {
+            return Err(anyhow::anyhow!(
+                "rustc checkout path {} must be a directory",
+                rustc_git.display()
+            ));
+        }
         return Ok(rustc_git);
     };
 
@@ -423,21 +1262,17 @@ fn prepare_rustc_checkout(verbose: bool) -> anyhow::Result<PathBuf> {
             // Download git history if we are on CI
             true,
         ) {
-            println!(
+            eprintln!(
                 "Cloning rustc into `{path}`. Use RUSTC_GIT environment variable to override the location of the checkout"
             );
+            let upstream_url = config.forge.repo_url(&config.upstream_repo);
+            let mut args = vec!["git", "clone", "--filter=blob:none"];
+            if let Some(branch) = &config.upstream_branch {
+                args.extend(["--branch", branch]);
+            }
+            args.extend([upstream_url.as_str(), path]);
             // Stream stdout/stderr to the terminal, so that the user sees clone progress
-            stream_command(
-                &[
-                    "git",
-                    "clone",
-                    "--filter=blob:none",
-                    &format!("https://github.com/{DEFAULT_UPSTREAM_REPO}"),
-                    path,
-                ],
-                verbose,
-            )
-            .context("cannot clone rustc")?;
+            runner.stream(&args).context("cannot clone rustc")?;
         } else {
             return Err(anyhow::anyhow!("cannot continue without a rustc checkout"));
         }
@@ -446,32 +1281,599 @@ fn prepare_rustc_checkout(verbose: bool) -> anyhow::Result<PathBuf> {
 }
 
 /// Restores HEAD to `reset_to` on drop, unless `disarm` is called first.
-struct GitResetOnDrop {
+struct GitResetOnDrop<'a> {
     disarmed: bool,
     reset_to: String,
-    verbose: bool,
+    runner: &'a dyn CommandRunner,
 }
 
-impl GitResetOnDrop {
-    fn new(current_sha: String, verbose: bool) -> Self {
+impl<'a> GitResetOnDrop<'a> {
+    fn new(current_sha: String, runner: &'a dyn CommandRunner) -> Self {
         Self {
             disarmed: false,
             reset_to: current_sha,
-            verbose,
+            runner,
         }
     }
 
     fn disarm(&mut self) {
         self.disarmed = true;
     }
+
+    /// Changes the commit to which the branch is reset.
+    fn reset_to(&mut self, sha: String) {
+        self.reset_to = sha;
+    }
 }
 
-impl Drop for GitResetOnDrop {
+impl Drop for GitResetOnDrop<'_> {
     fn drop(&mut self) {
         if !self.disarmed {
             eprintln!("Reverting HEAD to {}", self.reset_to);
-            run_command(&["git", "reset", "--hard", &self.reset_to], self.verbose)
-                .expect(&format!("cannot reset current branch to {}", self.reset_to));
+            if let Err(error) = self.runner.run(&["git", "reset", "--hard", &self.reset_to]) {
+                eprintln!(
+                    "Cannot reset current branch to {}, reset it manually: {error:?}",
+                    self.reset_to
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::ScriptedRunner;
+    use crate::error::ErrorKind;
+    use crate::forge::Forge;
+    use crate::git::CliBackend;
+    use crate::utils::run_command_at;
+
+    /// Creates a repository with a single commit on `main` at `<dir>/rust-lang/rust`.
+    fn create_upstream_repo(dir: &Path) -> String {
+        let repo = dir.join("rust-lang/rust");
+        std::fs::create_dir_all(&repo).unwrap();
+        let git = |args: &[&str]| run_command_at(args, &repo, false).unwrap();
+        git(&["git", "init", "--initial-branch=main"]);
+        git(&[
+            "git",
+            "-c",
+            "user.name=test",
+            "-c",
+            "user.email=test@example.com",
+            "commit",
+            "--allow-empty",
+            "-m",
+            "initial",
+        ]);
+        git(&["git", "rev-parse", "HEAD"])
+    }
+
+    #[test]
+    fn resolve_upstream_sha_from_local_repository() {
+        let dir = std::env::temp_dir().join(format!("josh-sync-test-{}", std::process::id()));
+        let sha = create_upstream_repo(&dir);
+
+        let mut config: JoshConfig = toml::from_str("repo = \"foo\"\npath = \"foo\"").unwrap();
+        config.forge = Forge::File { path: dir.clone() };
+        let runner = SystemRunner::new(false);
+        assert_eq!(resolve_upstream_sha(&config, &runner).unwrap(), sha);
+
+        config.upstream_branch = Some("main".to_string());
+        assert_eq!(resolve_upstream_sha(&config, &runner).unwrap(), sha);
+
+        config.upstream_branch = Some("missing".to_string());
+        assert!(resolve_upstream_sha(&config, &runner).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn push_stages() {
+        let mut stage = PushStage::FetchBase;
+        let mut stages = vec![stage];
+        while stage != PushStage::Done {
+            stage = stage.next();
+            stages.push(stage);
+        }
+        assert_eq!(
+            stages,
+            [
+                PushStage::FetchBase,
+                PushStage::PushBase,
+                PushStage::PushThroughJosh,
+                PushStage::RoundtripCheck,
+                PushStage::Done
+            ]
+        );
+
+        let state = PushState {
+            username: "user".to_string(),
+            subtrees: vec![SubtreePush {
+                name: "stdarch".to_string(),
+                branch: "sync".to_string(),
+                base_upstream_sha: "a".repeat(40),
+                head: "b".repeat(40),
+                stage: PushStage::PushThroughJosh,
+                update: true,
+                previous_tip: Some("c".repeat(40)),
+                rustc_git: None,
+                open_pr: false,
+            }],
+        };
+        let serialized = toml::to_string_pretty(&state).unwrap();
+        assert!(serialized.contains("stage = \"push-through-josh\""));
+        assert_eq!(toml::from_str::<PushState>(&serialized).unwrap(), state);
+    }
+
+    #[test]
+    fn pull_state_roundtrip() {
+        let state = PullState {
+            subtree: "stdarch".to_string(),
+            upstream_sha: "c".repeat(40),
+            incoming_ref: "d".repeat(40),
+            orig_head: "a".repeat(40),
+            sha_pre_merge: "b".repeat(40),
+            num_roots_before: 2,
+            merge_message: "Merge ref 'abc' from rust-lang/rust\n\nUpstream ref: \"x\"\n"
+                .to_string(),
+            allow_noop: false,
+        };
+        let serialized = toml::to_string_pretty(&state).unwrap();
+        assert_eq!(toml::from_str::<PullState>(&serialized).unwrap(), state);
+    }
+
+    fn test_context(last_upstream_sha: Option<&str>) -> SyncContext {
+        let config: JoshConfig = toml::from_str("repo = \"foo\"\npath = \"foo\"").unwrap();
+        let subtree = config.subtrees().remove(0);
+        SyncContext {
+            config,
+            subtree,
+            last_upstream_sha: last_upstream_sha.map(|sha| sha.to_string()),
+            last_upstream_sha_path: PathBuf::from("rust-version"),
+        }
+    }
+
+    fn scripted_sync(context: SyncContext, runner: &Arc<ScriptedRunner>) -> GitSync {
+        let proxy = JoshProxy::from_path(PathBuf::from("josh-proxy"));
+        GitSync::new(context, proxy, false)
+            .with_runner(runner.clone())
+            .with_backend(Arc::new(CliBackend::new(runner.clone())))
+    }
+
+    fn test_pull_state(allow_noop: bool) -> PullState {
+        PullState {
+            subtree: "foo".to_string(),
+            upstream_sha: "upstream".to_string(),
+            incoming_ref: "incoming".to_string(),
+            orig_head: "orig".to_string(),
+            sha_pre_merge: "pre-merge".to_string(),
+            num_roots_before: 1,
+            merge_message: "Merge".to_string(),
+            allow_noop,
+        }
+    }
+
+    #[test]
+    fn pull_without_upstream_changes() {
+        let runner = Arc::new(
+            ScriptedRunner::new()
+                .expect("git status --untracked-files=no --porcelain", "")
+                .expect(
+                    "git rev-parse --git-path josh-sync-pull.toml",
+                    "missing/josh-sync-pull.toml",
+                )
+                .expect("git rev-parse HEAD", "orig"),
+        );
+        let upstream_sha = "a".repeat(40);
+        let sync = scripted_sync(test_context(Some(&upstream_sha)), &runner);
+        let result = sync.rustc_pull(Some(upstream_sha.clone()), false);
+        assert!(matches!(result, Err(Error::NothingToPull)));
+        // Josh was not needed, and nothing was committed
+        assert!(runner.is_done());
+        assert_eq!(runner.calls().len(), 3);
+
+        let runner = Arc::new(ScriptedRunner::new().expect(
+            "git status --untracked-files=no --porcelain",
+            " M src/lib.rs",
+        ));
+        let sync = scripted_sync(test_context(Some(&upstream_sha)), &runner);
+        let result = sync.rustc_pull(Some("b".repeat(40)), false);
+        assert!(matches!(result, Err(Error::DirtyWorktree)));
+    }
+
+    #[test]
+    fn pull_merges_upstream_changes() {
+        let dir = std::env::temp_dir().join(format!("josh-sync-pull-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut context = test_context(Some(&"a".repeat(40)));
+        context.last_upstream_sha_path = dir.join("rust-version");
+        context.config.messages.prep_commit = "Prepare".to_string();
+        context.config.messages.merge_commit =
+            "Merge {{upstream_sha_short}}\n\n{{changelog}}".to_string();
+        let rust_version = context.last_upstream_sha_path.to_str().unwrap().to_string();
+        let upstream_sha = "b".repeat(40);
+        let josh_url = JoshProxy::already_running(42)
+            .start(&context.config)
+            .unwrap()
+            .git_url(
+                "rust-lang/rust",
+                Some(&upstream_sha),
+                &context.subtree.construct_josh_filter().unwrap(),
+            );
+        let log = "m1\0base c1\0Auto merge of #5 - a:b, r=c\n\nFix a bug\n\x1e\n\
+            c1\0base\0Fix the bug\n\x1e";
+
+        let runner = Arc::new(
+            ScriptedRunner::new()
+                .expect("git status --untracked-files=no --porcelain", "")
+                .expect(
+                    "git rev-parse --git-path josh-sync-pull.toml",
+                    "missing/josh-sync-pull.toml",
+                )
+                .expect("git rev-parse HEAD", "orig")
+                .expect(&format!("git add {rust_version}"), "")
+                .expect(
+                    &format!("git commit {rust_version} --no-verify -m Prepare"),
+                    "",
+                )
+                .expect(&format!("git fetch {josh_url}"), "")
+                .expect("git rev-list HEAD --max-parents=0 --count", "1")
+                .expect("git rev-parse HEAD", "pre-merge")
+                .expect("git rev-parse FETCH_HEAD", "incoming")
+                .expect(
+                    "git log --topo-order --format=%H%x00%P%x00%B%x1e HEAD..incoming",
+                    log,
+                )
+                .expect(
+                    "git merge FETCH_HEAD --no-verify --no-ff -m Merge bbbbbbbbbbbb\n\n\
+                    - rust-lang/rust#5: Fix a bug\n  - c1 Fix the bug\n",
+                    "",
+                )
+                .expect("git rev-parse HEAD", "merge")
+                .expect_failure("git diff --exit-code pre-merge")
+                .expect("git rev-list HEAD --max-parents=0 --count", "1"),
+        );
+        let proxy = JoshProxy::already_running(42);
+        let sync = GitSync::new(context, proxy, false)
+            .with_runner(runner.clone())
+            .with_backend(Arc::new(CliBackend::new(runner.clone())));
+        let result = sync.rustc_pull(Some(upstream_sha.clone()), false).unwrap();
+        assert_eq!(result.filtered_sha, "incoming");
+        assert_eq!(result.merge_commit.as_deref(), Some("merge"));
+        assert!(
+            result
+                .merge_commit_message
+                .contains("rust-lang/rust#5: Fix a bug")
+        );
+        assert!(runner.is_done());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("rust-version")).unwrap(),
+            format!("{upstream_sha}\n")
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pull_requires_full_upstream_sha() {
+        let runner = Arc::new(ScriptedRunner::new());
+        let sync = scripted_sync(test_context(Some("upstream")), &runner);
+        for sha in ["abc", "not a sha", &"g".repeat(40)] {
+            let result = sync.rustc_pull(Some(sha.to_string()), false);
+            assert!(matches!(result, Err(Error::InvalidUpstreamCommit(_))));
+        }
+        assert!(runner.calls().is_empty());
+    }
+
+    /// Finishes a pull of [`test_pull_state`], running the git commands with `runner`.
+    fn finish_scripted_pull(runner: &Arc<ScriptedRunner>, allow_noop: bool) -> Result<PullResult> {
+        let context = test_context(Some("upstream"));
+        let git = CliBackend::new(runner.clone());
+        let git_reset = GitResetOnDrop::new("orig".to_string(), runner.as_ref());
+        let state = test_pull_state(allow_noop);
+        finish_pull(&context, &state, git_reset, runner.as_ref(), &git)
+    }
+
+    #[test]
+    fn finish_pull_rolls_back_noop_merges() {
+        // No merge commit was created
+        let runner = Arc::new(
+            ScriptedRunner::new()
+                .expect("git rev-parse HEAD", "pre-merge")
+                .expect("git reset --hard orig", ""),
+        );
+        let result = finish_scripted_pull(&runner, false);
+        assert!(matches!(result, Err(Error::NothingToPull)));
+        assert!(runner.is_done());
+
+        // A merge commit was created, but it does not change anything
+        let runner = Arc::new(
+            ScriptedRunner::new()
+                .expect("git rev-parse HEAD", "merge")
+                .expect("git diff --exit-code pre-merge", "")
+                .expect("git reset --hard orig", ""),
+        );
+        let result = finish_scripted_pull(&runner, false);
+        assert!(matches!(result, Err(Error::NothingToPull)));
+        assert!(runner.is_done());
+
+        // Empty merges are kept with `allow_noop`
+        let runner = Arc::new(
+            ScriptedRunner::new()
+                .expect("git rev-parse HEAD", "merge")
+                .expect("git rev-list HEAD --max-parents=0 --count", "1"),
+        );
+        let result = finish_scripted_pull(&runner, true).unwrap();
+        assert_eq!(result.merge_commit.as_deref(), Some("merge"));
+        assert!(runner.is_done());
+    }
+
+    #[test]
+    fn finish_pull_checks_root_commits() {
+        let pull = |roots: &str| {
+            let runner = Arc::new(
+                ScriptedRunner::new()
+                    .expect("git rev-parse HEAD", "merge")
+                    .expect_failure("git diff --exit-code pre-merge")
+                    .expect("git rev-list HEAD --max-parents=0 --count", roots),
+            );
+            let result = finish_scripted_pull(&runner, false);
+            // The merge is kept, even if it has introduced a new root commit
+            assert!(runner.is_done());
+            result
+        };
+
+        let result = pull("1").unwrap();
+        assert_eq!(result.filtered_sha, "incoming");
+        assert_eq!(result.merge_commit.as_deref(), Some("merge"));
+        assert!(matches!(pull("2"), Err(Error::NewRootCommit)));
+    }
+
+    #[test]
+    fn continue_pull_keeps_merge_after_post_pull_failure() {
+        let dir = std::env::temp_dir().join(format!("josh-sync-continue-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let state_path = dir.join("josh-sync-pull.toml");
+        let state_path = state_path.to_str().unwrap();
+        test_pull_state(false)
+            .save(
+                &ScriptedRunner::new()
+                    .expect("git rev-parse --git-path josh-sync-pull.toml", state_path),
+            )
+            .unwrap();
+        let mut context = test_context(Some("upstream"));
+        context.subtree.post_pull = vec![PostPullOperation {
+            cmd: vec!["cargo".to_string(), "fmt".to_string()],
+            commit_message: "Format".to_string(),
+        }];
+        let contexts = [context];
+
+        let runner = Arc::new(
+            ScriptedRunner::new()
+                .expect("git rev-parse --git-path josh-sync-pull.toml", state_path)
+                .expect("git rev-parse -q --verify MERGE_HEAD", "incoming")
+                .expect("git diff --name-only --diff-filter=U", "")
+                .expect("git commit --no-verify -m Merge", "")
+                .expect("git status --untracked-files=no --porcelain", "")
+                .expect("git rev-parse HEAD", "merge")
+                .expect("git rev-parse HEAD", "merge")
+                .expect_failure("git diff --exit-code pre-merge")
+                .expect("git rev-parse HEAD", "merge")
+                .expect_failure("cargo fmt")
+                .expect("git reset --hard merge", ""),
+        );
+        let result = continue_pull(&contexts, runner.as_ref(), &CliBackend::new(runner.clone()));
+        assert!(result.is_err());
+        assert!(runner.is_done());
+        // The pull can be continued again
+        assert!(Path::new(state_path).is_file());
+
+        let runner = Arc::new(
+            ScriptedRunner::new()
+                .expect("git rev-parse --git-path josh-sync-pull.toml", state_path)
+                .expect_failure("git rev-parse -q --verify MERGE_HEAD")
+                .expect("git status --untracked-files=no --porcelain", "")
+                .expect("git rev-parse HEAD", "merge")
+                .expect("git rev-parse HEAD", "merge")
+                .expect_failure("git diff --exit-code pre-merge")
+                .expect("git rev-parse HEAD", "merge")
+                .expect("cargo fmt", "")
+                .expect("git diff --exit-code merge", "")
+                .expect("git rev-list HEAD --max-parents=0 --count", "1")
+                .expect("git rev-parse --git-path josh-sync-pull.toml", state_path),
+        );
+        let result =
+            continue_pull(&contexts, runner.as_ref(), &CliBackend::new(runner.clone())).unwrap();
+        assert_eq!(result.merge_commit.as_deref(), Some("merge"));
+        assert!(runner.is_done());
+        assert!(!Path::new(state_path).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_messages_of_latest_pull() {
+        let mut config: JoshConfig = toml::from_str(
+            r#"
+repo = "foo"
+path = "foo"
+post-pull = [{ cmd = ["cargo", "fmt"], commit-message = "Format" }]
+"#,
+        )
+        .unwrap();
+        let runner = ScriptedRunner::new()
+            .expect("git log -1 --format=%P%n%B HEAD", "merge\nFormat")
+            .expect(
+                "git log -1 --format=%P%n%B merge",
+                "prep incoming\nMerge\n\nChangelog",
+            )
+            .expect(
+                "git diff-tree --no-commit-id --name-only -r prep",
+                "rust-version",
+            )
+            .expect(
+                "git log -1 --format=%P%n%B prep^",
+                "old-merge other\nSome PR",
+            )
+            .expect(
+                "git diff-tree --no-commit-id --name-only -r old-merge",
+                "src/lib.rs",
+            );
+        assert_eq!(
+            latest_pull_merge_messages(&config, Path::new("./rust-version"), &runner).unwrap(),
+            ["Merge\n\nChangelog"]
+        );
+        assert!(runner.is_done());
+
+        // A merge of a branch that does not update `rust-version`
+        config.post_pull.clear();
+        let runner = ScriptedRunner::new()
+            .expect("git log -1 --format=%P%n%B HEAD", "a b\nMerge PR")
+            .expect(
+                "git diff-tree --no-commit-id --name-only -r a",
+                "src/lib.rs",
+            );
+        assert!(
+            latest_pull_merge_messages(&config, Path::new("rust-version"), &runner)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn updated_branch_has_to_be_based_on_last_pull() {
+        const FETCH_BASE: &str = "git fetch https://github.com/rust-lang/rust base";
+        const FORK: &str = "https://github.com/user/rust";
+        let push = SubtreePush {
+            name: "foo".to_string(),
+            branch: "sync".to_string(),
+            base_upstream_sha: "base".to_string(),
+            head: "head".to_string(),
+            stage: PushStage::FetchBase,
+            update: true,
+            previous_tip: None,
+            rustc_git: None,
+            open_pr: false,
+        };
+        let rustc_git = Path::new("rustc");
+
+        // The base is fetched before it is compared with the branch
+        let runner = Arc::new(
+            ScriptedRunner::new()
+                .expect(FETCH_BASE, "")
+                .expect("git fetch https://github.com/user/rust sync", "")
+                .expect("git rev-parse FETCH_HEAD", "tip")
+                .expect("git merge-base tip base", "base"),
+        );
+        let sync = scripted_sync(test_context(Some("base")), &runner);
+        let previous_tip = sync.fetch_base(rustc_git, FORK, &push).unwrap();
+        assert_eq!(previous_tip.as_deref(), Some("tip"));
+        assert!(runner.is_done());
+
+        let runner = Arc::new(
+            ScriptedRunner::new()
+                .expect(FETCH_BASE, "")
+                .expect("git fetch https://github.com/user/rust sync", "")
+                .expect("git rev-parse FETCH_HEAD", "tip")
+                .expect("git merge-base tip base", "older-base"),
+        );
+        let sync = scripted_sync(test_context(Some("base")), &runner);
+        let error = sync.fetch_base(rustc_git, FORK, &push).unwrap_err();
+        assert!(matches!(error, Error::BranchBaseMismatch { .. }));
+        assert_eq!(error.kind(), ErrorKind::ForkBranch);
+        assert!(
+            error
+                .to_string()
+                .contains("is based on a different upstream commit")
+        );
+
+        let runner = Arc::new(
+            ScriptedRunner::new()
+                .expect(FETCH_BASE, "")
+                .expect_failure("git fetch https://github.com/user/rust sync"),
+        );
+        let sync = scripted_sync(test_context(Some("base")), &runner);
+        let result = sync.fetch_base(rustc_git, FORK, &push);
+        assert!(matches!(result, Err(Error::BranchMissing { .. })));
+    }
+
+    #[test]
+    fn cleanup_restores_updated_branch() {
+        let dir = std::env::temp_dir().join(format!("josh-sync-cleanup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let state_path = dir.join("josh-sync-push.toml");
+        let state_path = state_path.to_str().unwrap();
+        let push = |name: &str, stage, previous_tip: Option<&str>| SubtreePush {
+            name: name.to_string(),
+            branch: format!("sync-{name}"),
+            base_upstream_sha: "base".to_string(),
+            head: "head".to_string(),
+            stage,
+            update: previous_tip.is_some(),
+            previous_tip: previous_tip.map(|tip| tip.to_string()),
+            rustc_git: previous_tip.map(|_| PathBuf::from("rustc")),
+            open_pr: false,
+        };
+        // The Josh push of an updated branch has failed after the branch was reset to the base
+        let state = PushState {
+            username: "user".to_string(),
+            subtrees: vec![
+                push("a", PushStage::PushThroughJosh, Some("tip-a")),
+                push("b", PushStage::RoundtripCheck, Some("tip-b")),
+                push("c", PushStage::RoundtripCheck, None),
+            ],
+        };
+        state
+            .save(
+                &ScriptedRunner::new()
+                    .expect("git rev-parse --git-path josh-sync-push.toml", state_path),
+            )
+            .unwrap();
+
+        let config: JoshConfig = toml::from_str("repo = \"foo\"\npath = \"foo\"").unwrap();
+        let runner = ScriptedRunner::new()
+            .expect("git rev-parse --git-path josh-sync-push.toml", state_path)
+            .expect(
+                "git push --force-with-lease=refs/heads/sync-a:base https://github.com/user/rust tip-a:refs/heads/sync-a",
+                "",
+            )
+            .expect(
+                "git ls-remote https://github.com/user/rust refs/heads/sync-b",
+                "pushed\trefs/heads/sync-b",
+            )
+            .expect(
+                "git push --force-with-lease=refs/heads/sync-b:pushed https://github.com/user/rust tip-b:refs/heads/sync-b",
+                "",
+            )
+            .expect(
+                "git push https://github.com/user/rust --delete sync-c",
+                "",
+            )
+            .expect("git rev-parse --git-path josh-sync-push.toml", state_path);
+        cleanup_push(&config, &runner).unwrap();
+        assert!(runner.is_done());
+        assert!(!Path::new(state_path).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn roundtrip_check_compares_fetched_branch() {
+        let runner = Arc::new(
+            ScriptedRunner::new()
+                .expect("git rev-parse HEAD", "head")
+                .expect("git rev-parse FETCH_HEAD", "head")
+                .expect("git rev-parse HEAD", "head")
+                .expect("git rev-parse FETCH_HEAD", "other"),
+        );
+        let sync = scripted_sync(test_context(Some("upstream")), &runner);
+        sync.roundtrip_check(&sync.context.subtree).unwrap();
+        match sync.roundtrip_check(&sync.context.subtree) {
+            Err(Error::NonRoundtripPush { expected, actual }) => {
+                assert_eq!((expected.as_str(), actual.as_str()), ("head", "other"));
+            }
+            _ => panic!("expected a non-roundtrip push"),
         }
+        assert!(runner.is_done());
     }
 }
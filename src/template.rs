@@ -0,0 +1,186 @@
+//! A small template engine for commit messages and pull request texts.
+//!
+//! Templates are plain text with `{{variable}}` placeholders. A `{{#if variable}}...{{/if}}`
+//! section is only rendered if the variable is non-empty.
+
+/// A parsed template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Node {
+    Text(String),
+    Variable(String),
+    If { variable: String, body: Vec<Node> },
+}
+
+impl Template {
+    pub fn parse(template: &str) -> anyhow::Result<Self> {
+        // Stack of the sections that are being parsed, the top-level one is at the bottom
+        let mut sections: Vec<(Option<String>, Vec<Node>)> = vec![(None, vec![])];
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            let nodes = &mut sections.last_mut().unwrap().1;
+            if start > 0 {
+                nodes.push(Node::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find("}}")
+                .map(|end| start + end)
+                .ok_or_else(|| anyhow::anyhow!("unclosed `{{{{` in template"))?;
+            let tag = rest[start + 2..end].trim();
+            rest = &rest[end + 2..];
+
+            if let Some(variable) = tag.strip_prefix("#if ") {
+                sections.push((Some(parse_variable(variable)?), vec![]));
+            } else if tag == "/if" {
+                let (Some(variable), body) = sections.pop().unwrap() else {
+                    return Err(anyhow::anyhow!(
+                        "`{{{{/if}}}}` without a matching `{{{{#if}}}}`"
+                    ));
+                };
+                sections
+                    .last_mut()
+                    .unwrap()
+                    .1
+                    .push(Node::If { variable, body });
+            } else {
+                nodes.push(Node::Variable(parse_variable(tag)?));
+            }
+        }
+        if !rest.is_empty() {
+            sections
+                .last_mut()
+                .unwrap()
+                .1
+                .push(Node::Text(rest.to_string()));
+        }
+
+        let (variable, nodes) = sections.pop().unwrap();
+        if let Some(variable) = variable {
+            return Err(anyhow::anyhow!("unclosed `{{{{#if {variable}}}}}`"));
+        }
+        Ok(Self { nodes })
+    }
+
+    /// Returns the names of all variables used by the template.
+    pub fn variables(&self) -> Vec<&str> {
+        fn collect<'a>(nodes: &'a [Node], out: &mut Vec<&'a str>) {
+            for node in nodes {
+                match node {
+                    Node::Text(_) => {}
+                    Node::Variable(variable) => out.push(variable),
+                    Node::If { variable, body } => {
+                        out.push(variable);
+                        collect(body, out);
+                    }
+                }
+            }
+        }
+        let mut variables = vec![];
+        collect(&self.nodes, &mut variables);
+        variables
+    }
+
+    /// Renders the template. Fails if it uses a variable that is not in `variables`.
+    pub fn render(&self, variables: &[(&str, &str)]) -> anyhow::Result<String> {
+        fn render_nodes(
+            nodes: &[Node],
+            variables: &[(&str, &str)],
+            out: &mut String,
+        ) -> anyhow::Result<()> {
+            let lookup = |name: &str| {
+                variables
+                    .iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| *value)
+                    .ok_or_else(|| anyhow::anyhow!("unknown template variable `{name}`"))
+            };
+            for node in nodes {
+                match node {
+                    Node::Text(text) => out.push_str(text),
+                    Node::Variable(variable) => out.push_str(lookup(variable)?),
+                    Node::If { variable, body } => {
+                        if !lookup(variable)?.is_empty() {
+                            render_nodes(body, variables, out)?;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+        let mut out = String::new();
+        render_nodes(&self.nodes, variables, &mut out)?;
+        Ok(out)
+    }
+}
+
+/// Checks that `template` is well-formed and only uses the `allowed` variables.
+pub fn check_template(template: &str, allowed: &[&str]) -> anyhow::Result<()> {
+    let template = Template::parse(template)?;
+    if let Some(unknown) = template
+        .variables()
+        .into_iter()
+        .find(|variable| !allowed.contains(variable))
+    {
+        return Err(anyhow::anyhow!(
+            "unknown variable `{unknown}`, available variables: {}",
+            allowed.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Parses and renders `template` in one go.
+pub fn render(template: &str, variables: &[(&str, &str)]) -> anyhow::Result<String> {
+    Template::parse(template)?.render(variables)
+}
+
+fn parse_variable(name: &str) -> anyhow::Result<String> {
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(anyhow::anyhow!("invalid template variable `{name}`"));
+    }
+    Ok(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_variables() {
+        assert_eq!(
+            render(
+                "Merge {{sha}} from {{ repo }}.",
+                &[("sha", "abc"), ("repo", "rust-lang/rust")]
+            )
+            .unwrap(),
+            "Merge abc from rust-lang/rust."
+        );
+        assert!(render("{{missing}}", &[]).is_err());
+    }
+
+    #[test]
+    fn render_conditional_sections() {
+        let template = "a{{#if x}}[{{x}}{{#if y}}, {{y}}{{/if}}]{{/if}}b";
+        assert_eq!(render(template, &[("x", ""), ("y", "2")]).unwrap(), "ab");
+        assert_eq!(render(template, &[("x", "1"), ("y", "")]).unwrap(), "a[1]b");
+        assert_eq!(
+            render(template, &[("x", "1"), ("y", "2")]).unwrap(),
+            "a[1, 2]b"
+        );
+    }
+
+    #[test]
+    fn reject_malformed_templates() {
+        assert!(Template::parse("{{x").is_err());
+        assert!(Template::parse("{{#if x}}").is_err());
+        assert!(Template::parse("{{/if}}").is_err());
+        assert!(Template::parse("{{a b}}").is_err());
+        assert!(check_template("{{a}} {{#if b}}{{/if}}", &["a", "b"]).is_ok());
+        assert!(check_template("{{a}} {{c}}", &["a", "b"]).is_err());
+    }
+}
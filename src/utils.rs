@@ -1,6 +1,10 @@
+use crate::error::Error;
+use crate::git::GitBackend;
 use anyhow::Context;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 /// Run command and return its stdout.
 pub fn run_command<'a, Args: AsRef<[&'a str]>>(
@@ -10,9 +14,16 @@ pub fn run_command<'a, Args: AsRef<[&'a str]>>(
     run_command_at(args, &std::env::current_dir()?, verbose)
 }
 
-/// Run command while streaming stdout and stderr to the terminal.
+/// Run command while streaming its output to stderr of the terminal.
+/// Stdout is kept free for the results of josh-sync.
 pub fn stream_command<'a, Args: AsRef<[&'a str]>>(args: Args, verbose: bool) -> anyhow::Result<()> {
-    run_command_inner(args, &std::env::current_dir()?, false, verbose)?;
+    let args = args.as_ref();
+
+    let mut cmd = Command::new(args[0]);
+    cmd.args(&args[1..]);
+    cmd.stdout(std::io::stderr());
+
+    execute_command(cmd, false, verbose)?;
     Ok(())
 }
 
@@ -40,7 +51,7 @@ fn run_command_inner<'a, Args: AsRef<[&'a str]>>(
 }
 
 pub fn run_command_by_path<'a, Args: AsRef<[&'a str]>>(
-    cmd: &PathBuf,
+    cmd: &Path,
     args: Args,
     workdir: &Path,
     capture: bool,
@@ -55,55 +66,221 @@ pub fn run_command_by_path<'a, Args: AsRef<[&'a str]>>(
     execute_command(cmd, capture, verbose)
 }
 
+/// Run command and return its exit code and stdout, without treating a non-zero exit code
+/// as an error.
+pub fn run_command_with_exit_code<'a, Args: AsRef<[&'a str]>>(
+    args: Args,
+    verbose: bool,
+) -> anyhow::Result<(Option<i32>, String)> {
+    let args = args.as_ref();
+
+    let mut cmd = Command::new(args[0]);
+    cmd.args(&args[1..]);
+    configure_hermetic_git(&mut cmd);
+    if verbose {
+        eprintln!("+ {cmd:?}");
+    }
+    let out = cmd
+        .output()
+        .with_context(|| format!("cannot run command `{cmd:?}`"))?;
+    let stdout = String::from_utf8_lossy(out.stdout.trim_ascii()).to_string();
+    Ok((out.status.code(), stdout))
+}
+
 fn execute_command(mut cmd: Command, capture: bool, verbose: bool) -> anyhow::Result<String> {
+    configure_hermetic_git(&mut cmd);
     if verbose {
         eprintln!("+ {cmd:?}");
     }
     if capture {
-        let out = cmd.output().expect("command failed");
+        let out = cmd
+            .output()
+            .with_context(|| format!("cannot run command `{cmd:?}`"))?;
         let stdout = String::from_utf8_lossy(out.stdout.trim_ascii()).to_string();
         let stderr = String::from_utf8_lossy(out.stderr.trim_ascii()).to_string();
         if !out.status.success() {
-            Err(anyhow::anyhow!(
-                "Command `{cmd:?}` failed with exit code {:?}. STDOUT:\n{stdout}\nSTDERR:\n{stderr}",
-                out.status.code()
-            ))
+            Err(Error::CommandFailed {
+                command: format!("{cmd:?}"),
+                code: out.status.code(),
+                stdout,
+                stderr,
+            }
+            .into())
         } else {
             Ok(stdout)
         }
     } else {
         let status = cmd
             .spawn()
-            .expect("cannot spawn command")
-            .wait()
-            .expect("command failed");
+            .and_then(|mut child| child.wait())
+            .with_context(|| format!("cannot run command `{cmd:?}`"))?;
         if !status.success() {
-            Err(anyhow::anyhow!(
-                "Command `{cmd:?}` failed with exit code {:?}",
-                status.code()
-            ))
+            Err(Error::CommandFailed {
+                command: format!("{cmd:?}"),
+                code: status.code(),
+                stdout: String::new(),
+                stderr: String::new(),
+            }
+            .into())
         } else {
             Ok(String::new())
         }
     }
 }
 
+/// Whether git runs in a hermetic environment, see [`configure_hermetic_git`].
+static HERMETIC_GIT: AtomicBool = AtomicBool::new(true);
+
+/// Enables or disables the hermetic git environment for all following git commands.
+/// It is enabled by default.
+pub fn set_hermetic_git(enabled: bool) {
+    HERMETIC_GIT.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_hermetic_git() -> bool {
+    HERMETIC_GIT.load(Ordering::Relaxed)
+}
+
+/// Makes a git command ignore the global and system git config, apart from the entries that
+/// are needed to authenticate and to identify the user (see [`is_kept_git_config_key`]).
+/// Settings like `fetch.prunetags` would otherwise change the outcome of a sync.
+/// The config of the local repository is still used.
+fn configure_hermetic_git(cmd: &mut Command) {
+    if !is_hermetic_git() || Path::new(cmd.get_program()).file_stem() != Some("git".as_ref()) {
+        return;
+    }
+    if let Some(config) = hermetic_git_config() {
+        cmd.env("GIT_CONFIG_GLOBAL", config);
+        cmd.env("GIT_CONFIG_NOSYSTEM", "1");
+    }
+}
+
+/// Returns the path of a git config file with the kept entries of the global and system git
+/// config, which replaces the global config of the git commands. It is written once per process.
+/// Returns `None` if it cannot be written, in which case git uses the full config.
+fn hermetic_git_config() -> Option<&'static Path> {
+    static CONFIG: OnceLock<Option<PathBuf>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            write_hermetic_git_config()
+                .inspect_err(|error| {
+                    eprintln!(
+                        "Warning: cannot isolate git from the global git config, using it as is: {error:?}"
+                    )
+                })
+                .ok()
+        })
+        .as_deref()
+}
+
+fn write_hermetic_git_config() -> anyhow::Result<PathBuf> {
+    let mut entries = vec![];
+    for scope in ["--system", "--global"] {
+        // The command is executed directly, so that it sees the config that we want to filter.
+        // It fails if there is no config file of the scope.
+        let out = Command::new("git")
+            .args(["config", "--list", "--null", "--includes", scope])
+            .output()
+            .context("cannot run git")?;
+        if out.status.success() {
+            entries.extend(
+                parse_git_config_list(&String::from_utf8_lossy(&out.stdout))
+                    .into_iter()
+                    .filter(|(key, _)| is_kept_git_config_key(key)),
+            );
+        }
+    }
+
+    let dirs = directories::ProjectDirs::from("org", "rust-lang", "rustc-josh-sync")
+        .context("cannot determine cache directory for josh-sync")?;
+    let dir = dirs.cache_dir();
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("cannot create cache directory {}", dir.display()))?;
+    // Concurrent syncs may write the file at the same time, so it is replaced atomically.
+    let path = dir.join("gitconfig");
+    let tmp_path = dir.join(format!("gitconfig.{}", std::process::id()));
+    std::fs::write(&tmp_path, format_git_config(&entries))
+        .with_context(|| format!("cannot write {}", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, &path)
+        .with_context(|| format!("cannot write {}", path.display()))?;
+    Ok(path)
+}
+
+/// Returns true for the keys of the global and system git config that are kept in the
+/// hermetic git environment. Besides the identity of the user, credentials and URL rewrites,
+/// these are `safe.directory`, which is only read from the global and system config, and the
+/// transport settings (SSH command, HTTP proxies, extra headers and TLS) that some setups need
+/// to talk to remotes at all.
+pub(crate) fn is_kept_git_config_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    let (section, rest) = key.split_once('.').unwrap_or((&key, ""));
+    let name = rest.rsplit('.').next().unwrap_or_default();
+    match section {
+        "user" | "credential" => true,
+        "url" => name == "insteadof" || name == "pushinsteadof",
+        "safe" => name == "directory",
+        "core" => name == "sshcommand",
+        "http" => name.starts_with("ssl") || name.starts_with("proxy") || name == "extraheader",
+        _ => false,
+    }
+}
+
+/// Parses the output of `git config --list --null` into keys and values.
+/// Keys without a value (which mean `true`) have `None` as value.
+fn parse_git_config_list(output: &str) -> Vec<(String, Option<String>)> {
+    output
+        .split('\0')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once('\n') {
+            Some((key, value)) => (key.to_string(), Some(value.to_string())),
+            None => (entry.to_string(), None),
+        })
+        .collect()
+}
+
+/// Formats config entries as a git config file, keeping their order.
+fn format_git_config(entries: &[(String, Option<String>)]) -> String {
+    let quote = |s: &str| {
+        let escaped = s
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+            .replace('\t', "\\t");
+        format!("\"{escaped}\"")
+    };
+    let mut config = String::new();
+    for (key, value) in entries {
+        let Some((section, rest)) = key.split_once('.') else {
+            continue;
+        };
+        let (header, name) = match rest.rsplit_once('.') {
+            Some((subsection, name)) => (format!("[{section} {}]", quote(subsection)), name),
+            None => (format!("[{section}]"), rest),
+        };
+        config.push_str(&header);
+        config.push('\n');
+        match value {
+            Some(value) => config.push_str(&format!("\t{name} = {}\n", quote(value))),
+            None => config.push_str(&format!("\t{name}\n")),
+        }
+    }
+    config
+}
+
 /// Fail if there are files that need to be checked in.
-pub fn ensure_clean_git_state(verbose: bool) -> anyhow::Result<()> {
-    let read = run_command(
-        ["git", "status", "--untracked-files=no", "--porcelain"],
-        verbose,
-    )
-    .expect("cannot figure out if git state is clean");
-    if !read.is_empty() {
-        Err(anyhow::anyhow!("working directory must be clean"))
+pub fn ensure_clean_git_state(git: &dyn GitBackend) -> crate::error::Result<()> {
+    let clean = git
+        .is_clean()
+        .context("cannot figure out if git state is clean")?;
+    if !clean {
+        Err(Error::DirtyWorktree)
     } else {
         Ok(())
     }
 }
 
-pub fn get_current_head_sha(verbose: bool) -> anyhow::Result<String> {
-    run_command(&["git", "rev-parse", "HEAD"], verbose).context("failed to get current commit")
+pub fn get_current_head_sha(git: &dyn GitBackend) -> anyhow::Result<String> {
+    git.head_sha().context("failed to get current commit")
 }
 
 /// Ask a prompt to user and return true if they responded with `y`.
@@ -114,19 +291,68 @@ pub fn prompt(prompt: &str, default_response: bool) -> bool {
         return default_response;
     }
 
-    println!("{prompt} [y/n]");
-    read_line().to_lowercase() == "y"
+    eprintln!("{prompt} [y/n]");
+    read_line().is_ok_and(|line| line.to_lowercase() == "y")
 }
 
-pub fn read_line() -> String {
+pub fn read_line() -> std::io::Result<String> {
     let mut line = String::new();
-    std::io::stdin()
-        .read_line(&mut line)
-        .expect("cannot read line from stdin");
-    line.trim().to_string()
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
 }
 
 pub fn is_null_sha(s: &str) -> bool {
     let s = s.trim();
     !s.is_empty() && s.chars().all(|c| c == '0')
 }
+
+/// Returns true if `s` is a full 40-character hexadecimal commit SHA.
+pub fn is_full_sha(s: &str) -> bool {
+    s.len() == 40 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hermetic_git_config_entries() {
+        let output = "user.name\nJane Doe\0core.autocrlf\ntrue\0fetch.prunetags\ntrue\0\
+            url.git@github.com:.insteadof\nhttps://github.com/\0credential.helper\n\0\
+            credential.https://example.com.helper\nstore --file=\"a b\"\0safe.directory\n*\0\
+            http.sslbackend\nschannel\0http.proxy\nhttp://proxy:3128\0\
+            http.extraheader\nAuthorization: Basic abc\0http.postbuffer\n1000\0\
+            http.https://example.com.proxy\nhttp://other:3128\0\
+            core.sshcommand\nssh -i key\0core.pager\nless\0user.signingkey\0";
+        let entries: Vec<_> = parse_git_config_list(output)
+            .into_iter()
+            .filter(|(key, _)| is_kept_git_config_key(key))
+            .collect();
+        assert_eq!(
+            format_git_config(&entries),
+            r#"[user]
+	name = "Jane Doe"
+[url "git@github.com:"]
+	insteadof = "https://github.com/"
+[credential]
+	helper = ""
+[credential "https://example.com"]
+	helper = "store --file=\"a b\""
+[safe]
+	directory = "*"
+[http]
+	sslbackend = "schannel"
+[http]
+	proxy = "http://proxy:3128"
+[http]
+	extraheader = "Authorization: Basic abc"
+[http "https://example.com"]
+	proxy = "http://other:3128"
+[core]
+	sshcommand = "ssh -i key"
+[user]
+	signingkey
+"#
+        );
+    }
+}